@@ -1,6 +1,65 @@
 use clap::Parser;
-use std::fs;
+use rsbflib::{CellWidth, Dialect, EofBehavior, TapeConfig, TapeMode};
+use std::{fs, str::FromStr};
 use subprocess::{Exec, Redirection};
+#[cfg(feature = "aot")]
+use target_lexicon::Triple;
+
+// What rsbfc produces
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    // Executable compiled with cranelift and linked by the system linker
+    Native,
+    // Relocatable object file with a main function, needs libc
+    Object,
+    // Static linux executable that doesn't need a linker
+    Elf,
+    // Executable compiled from C by clang
+    C,
+    // WebAssembly module, see --wasi
+    Wasm,
+    // LLVM IR, for llc or opt
+    Llvm,
+    // Rust source with a run function that can be used as a module
+    Rust,
+    // Javascript for node or browsers, see js_translate
+    Js,
+    // Python 3 script
+    Python,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Target::Native),
+            "object" => Ok(Target::Object),
+            "elf" => Ok(Target::Elf),
+            "c" => Ok(Target::C),
+            "wasm" => Ok(Target::Wasm),
+            "llvm" => Ok(Target::Llvm),
+            "rust" => Ok(Target::Rust),
+            "js" => Ok(Target::Js),
+            "python" => Ok(Target::Python),
+            _ => Err(format!("unknown target: {}", s)),
+        }
+    }
+}
+
+impl Target {
+    // Targets that produce code which --code can print
+    fn is_source(self) -> bool {
+        matches!(
+            self,
+            Target::C
+                | Target::Llvm
+                | Target::Rust
+                | Target::Js
+                | Target::Python
+        )
+    }
+}
 
 // Compiles C to machine code
 fn cc(input: &str, binary_name: &str) -> String {
@@ -13,6 +72,58 @@ fn cc(input: &str, binary_name: &str) -> String {
         .stdout_str()
 }
 
+// Links an object file into an executable, $CC replaces cc if set
+#[cfg(feature = "aot")]
+fn link(object_name: &str, binary_name: &str) -> bool {
+    let linker = std::env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    Exec::cmd(linker)
+        .args(&["-o", binary_name, object_name])
+        .join()
+        .is_ok_and(|status| status.success())
+}
+
+// Writes a file that can be executed by anyone who can read it
+#[cfg(feature = "aot")]
+fn write_executable(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+// Parses --triple, the host is used without it
+#[cfg(feature = "aot")]
+fn target_triple(args: &Args) -> Triple {
+    match &args.triple {
+        None => Triple::host(),
+        Some(triple) => Triple::from_str(triple).unwrap_or_else(|err| {
+            eprintln!("error: invalid target triple {}: {}", triple, err);
+            std::process::exit(1);
+        }),
+    }
+}
+
+// Unwraps a result of the library, exits with its error otherwise
+fn or_exit<T>(result: Result<T, rsbflib::Error>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    })
+}
+
+// Prints code with --code, writes it to the output path otherwise
+fn write_source(args: &Args, code: &str) {
+    if args.code {
+        print!("{}", code);
+    } else {
+        fs::write(&args.output, code)
+            .expect("Something went wrong writing the code");
+    }
+}
+
 /// Brainfuck compiler
 #[derive(Parser, Debug)]
 #[clap(name="rsbfc", author, version, about, long_about = None)]
@@ -25,7 +136,24 @@ struct Args {
     #[clap(value_parser, default_value = "a.out")]
     output: String,
 
-    /// Output C code instead of compiling
+    /// What to produce: native, object, elf, c, wasm, llvm, rust, js or
+    /// python. native by default, c with --code. llvm IR uses opaque
+    /// pointers, it needs LLVM 15 or newer, or -opaque-pointers with LLVM 14
+    #[clap(long, value_parser)]
+    target: Option<Target>,
+
+    /// Target triple of native, object and elf, the host by default, e.g.
+    /// aarch64-unknown-linux-gnu. Set CC to a cross compiler for native
+    #[clap(long, value_parser)]
+    triple: Option<String>,
+
+    /// Import WASI functions in WebAssembly modules instead of the ones in
+    /// the "env" module
+    #[clap(long, value_parser)]
+    wasi: bool,
+
+    /// Output the code of source targets (c, llvm, rust, js and python)
+    /// instead of writing or compiling it, C unless --target is given
     #[clap(
         long,
         // conflicts_with = "cranelift",
@@ -33,18 +161,158 @@ struct Args {
         value_parser
     )]
     code: bool,
+
+    /// Amount of cells on the tape, the initial amount with --tape-mode grow
+    #[clap(long, value_parser = parse_tape_size, default_value = "30000")]
+    tape_size: usize,
+
+    /// What accessing a cell outside of the tape does: wrap, error or grow.
+    /// strict is error, but moving the pointer off the tape is one too
+    #[clap(long, value_parser, default_value = "wrap")]
+    tape_mode: TapeMode,
+
+    /// Bits in a cell: 8, 16 or 32
+    #[clap(long, value_parser, default_value = "8")]
+    cell_width: CellWidth,
+
+    /// What `,` does at end of input: unchanged, zero or minus-one
+    #[clap(long, value_parser, default_value = "zero")]
+    eof: EofBehavior,
+
+    /// Make `#` print the cells up to RADIUS away from the pointer to
+    /// stderr, only the c target supports it
+    #[clap(long, value_parser, value_name = "RADIUS")]
+    debug_dump: Option<usize>,
+}
+
+fn parse_tape_size(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("the tape needs at least one cell".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("not a number: {}", s)),
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let contents = fs::read_to_string(args.file)
+    let target = match args.target {
+        Some(target) => target,
+        None if args.code => Target::C,
+        None => Target::Native,
+    };
+    if args.code && !target.is_source() {
+        eprintln!(
+            "error: --code needs a source target: c, llvm, rust, js or python"
+        );
+        std::process::exit(1);
+    }
+    if args.debug_dump.is_some() && !matches!(target, Target::C) {
+        eprintln!("error: --debug-dump is only supported by the c target");
+        std::process::exit(1);
+    }
+    let contents = fs::read_to_string(&args.file)
         .expect("Something went wrong reading the file");
-    let tokens = rsbflib::tokenize(&contents);
-    let optimized_tokens = rsbflib::optimize(tokens);
-    let c_code = rsbflib::c_translate(optimized_tokens);
-    if args.code {
-        print!("{}", c_code);
-    } else {
-        print!("{}", cc(&c_code, &(args.output)));
+    let dialect = Dialect {
+        debug_dump: args.debug_dump,
+    };
+    let program = match rsbflib::parse(&contents, dialect) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprint!("{}", error.render(&args.file, &contents));
+            }
+            std::process::exit(1);
+        }
+    };
+    let tape = or_exit(TapeConfig::new(
+        args.tape_size,
+        args.tape_mode,
+        args.cell_width,
+    ));
+    let program = rsbflib::optimize(program, tape.mode);
+    match target {
+        Target::Llvm => {
+            let code = rsbflib::llvm_translate(&program, &tape, args.eof);
+            write_source(&args, &or_exit(code));
+        }
+        Target::Rust => {
+            let code = rsbflib::rust_translate(&program, &tape, args.eof);
+            write_source(&args, &or_exit(code));
+        }
+        Target::Js => {
+            let code = rsbflib::js_translate(&program, &tape, args.eof);
+            write_source(&args, &or_exit(code));
+        }
+        Target::Python => {
+            let code = rsbflib::python_translate(&program, &tape, args.eof);
+            write_source(&args, &or_exit(code));
+        }
+        #[cfg(feature = "aot")]
+        Target::Native | Target::Object => {
+            let object = match rsbflib::aot::compile_object(
+                &program,
+                args.eof,
+                tape,
+                target_triple(&args),
+            ) {
+                Ok(object) => object,
+                Err(error) => {
+                    eprint!("{}", error.render(&args.file, &contents));
+                    std::process::exit(1);
+                }
+            };
+            if let Target::Object = target {
+                fs::write(&args.output, object)
+                    .expect("Something went wrong writing the object file");
+                return;
+            }
+            let object_name = format!("{}.o", args.output);
+            fs::write(&object_name, object)
+                .expect("Something went wrong writing the object file");
+            let linked = link(&object_name, &args.output);
+            let _ = fs::remove_file(&object_name);
+            if !linked {
+                eprintln!("error: linking {} failed", args.output);
+                std::process::exit(1);
+            }
+        }
+        #[cfg(feature = "aot")]
+        Target::Elf => {
+            let elf = match rsbflib::elf::compile_elf(
+                &program,
+                args.eof,
+                tape,
+                target_triple(&args),
+            ) {
+                Ok(elf) => elf,
+                Err(error) => {
+                    eprint!("{}", error.render(&args.file, &contents));
+                    std::process::exit(1);
+                }
+            };
+            write_executable(&args.output, &elf)
+                .expect("Something went wrong writing the executable");
+        }
+        #[cfg(not(feature = "aot"))]
+        Target::Native | Target::Object | Target::Elf => {
+            panic!("AOT Feature was not enabled at compile time")
+        }
+        Target::Wasm => {
+            let module =
+                rsbflib::wasm_translate(&program, &tape, args.eof, args.wasi);
+            fs::write(&args.output, or_exit(module))
+                .expect("Something went wrong writing the module");
+        }
+        Target::C if args.code => {
+            print!(
+                "{}",
+                or_exit(rsbflib::c_translate(&program, &tape, args.eof))
+            )
+        }
+        Target::C => {
+            let c_code =
+                or_exit(rsbflib::c_translate(&program, &tape, args.eof));
+            print!("{}", cc(&c_code, &(args.output)));
+        }
     }
 }
@@ -1,189 +1,288 @@
 use clap::Parser;
-use nohash_hasher::NoHashHasher;
-use rsbflib::{BracketState, TokenKind};
+use rsbflib::{
+    CellWidth, Debugger, Dialect, Engine, EofBehavior, Error, Interpreter,
+    Limits, Profiler, TapeConfig, TapeMode,
+};
 use std::{
-    collections::HashMap,
-    error::Error,
     fs,
-    hash::BuildHasherDefault,
     io::{self, Write},
     path::PathBuf,
+    str::FromStr,
+    time::Duration,
 };
 
 #[cfg(feature = "jit")]
 use rsbflib::codegen;
 
-fn generate_jumping_map(
-    tokens: &Vec<rsbflib::Token>,
-) -> Result<
-    HashMap<usize, usize, BuildHasherDefault<NoHashHasher<usize>>>,
-    Box<dyn Error>,
-> {
-    let mut map: HashMap<
-        usize,
-        usize,
-        BuildHasherDefault<NoHashHasher<usize>>,
-    > = HashMap::with_capacity_and_hasher(
-        tokens
-            .iter()
-            .filter(|x| x.kind == TokenKind::Bracket(BracketState::Open))
-            .count(),
-        BuildHasherDefault::default(),
-    );
-    let mut open_bracket_index_stack: Vec<usize> = vec![];
-    for (i, token) in tokens.iter().enumerate() {
-        match token.kind {
-            TokenKind::Bracket(BracketState::Open) => {
-                open_bracket_index_stack.push(i)
-            }
-            TokenKind::Bracket(BracketState::Closed) => {
-                map.insert(
-                    open_bracket_index_stack
-                        .pop()
-                        .ok_or("Too many closing brackets")?,
-                    i,
-                );
-            }
-            _ => { /* We don't care */ }
-        }
-    }
+// What --emit prints instead of running the program
+#[derive(Debug, Clone, Copy)]
+enum Emit {
+    // Cranelift IR before and after optimization
+    Clif,
+    // Disassembly of the JIT compiled code
+    Asm,
+}
 
-    if open_bracket_index_stack.len() > 0 {
-        Err("Too many opening brackets")?
-    }
+impl FromStr for Emit {
+    type Err = String;
 
-    Ok(map)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clif" => Ok(Emit::Clif),
+            "asm" => Ok(Emit::Asm),
+            _ => Err(format!("unknown emit kind: {}", s)),
+        }
+    }
 }
 
-const MEM_SIZE: usize = 30000;
+/// Brainfuck interpreter
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Brainfuck file
+    #[clap(value_parser)]
+    file: PathBuf,
 
-fn interpret(tokens: Vec<rsbflib::Token>) {
-    let mut memory = [0isize; MEM_SIZE];
-    let mut mempos: usize = 0;
-    let mut pos: usize = 0;
-    let mut loop_stack: Vec<usize> = vec![];
+    /// JIT code instead of interpreting
+    #[clap(short, long, value_parser)]
+    jit: bool,
 
-    let mut stdout = io::stdout();
+    /// What `,` does at end of input: unchanged, zero or minus-one
+    #[clap(long, value_parser, default_value = "zero")]
+    eof: EofBehavior,
 
-    let mut temp_stdio_buf = [0u8; 1];
+    /// Amount of cells on the tape, the initial amount with --tape-mode grow
+    #[clap(long, value_parser = parse_tape_size, default_value = "30000")]
+    tape_size: usize,
 
-    let jumping_map = generate_jumping_map(&tokens)
-        .expect("Couldn't generate jumping tables");
+    /// What accessing a cell outside of the tape does: wrap, error or grow.
+    /// strict is error, but moving the pointer off the tape is one too
+    #[clap(long, value_parser, default_value = "wrap")]
+    tape_mode: TapeMode,
 
-    while tokens.len() > pos {
-        let token = &tokens[pos];
-        match &token.kind {
-            TokenKind::Output => {
-                (memory[mempos] as u8 as char).encode_utf8(&mut temp_stdio_buf);
-                stdout
-                    .write(&temp_stdio_buf)
-                    .expect("Couldn't write to stdout");
-                // updates stdout per char but is much slower in a slow terminal
-                // stdout.flush().expect("Couldn't flush stdout");
-            }
-            TokenKind::Input => {
-                todo!("input char (,)")
-            }
-            TokenKind::Clear => {
-                memory[mempos] = 0;
-            }
-            TokenKind::ValMod(value) => {
-                memory[mempos] += value;
-            }
-            TokenKind::PosMod(value) => {
-                mempos = mempos.wrapping_add(*value as usize);
-                if mempos >= MEM_SIZE {
-                    mempos %= MEM_SIZE
-                }
-            }
-            TokenKind::Bracket(BracketState::Open) => {
-                if memory[mempos] as u8 == 0 {
-                    pos = *jumping_map
-                        .get(&pos)
-                        .expect("Opened loop never closed");
-                } else {
-                    loop_stack.push(pos);
-                }
-            }
-            TokenKind::Bracket(BracketState::Closed) => {
-                if memory[mempos] as u8 != 0 {
-                    pos = *loop_stack.last().expect("Closed loop never opened")
-                } else {
-                    loop_stack.pop();
-                }
-            }
-            TokenKind::Copy(offset) => {
-                let x = mempos.wrapping_add(*offset as usize);
-                // this if statement slows it down so much, and since the bug
-                // is extremely rare, this code is commented
-                // if x >= MEM_SIZE {
-                //     x %= MEM_SIZE
-                // }
-                memory[x] += memory[mempos];
-            }
-            TokenKind::Comment => {}
-        }
-        pos += 1;
-    }
-}
+    /// Bits in a cell: 8, 16 or 32
+    #[clap(long, value_parser, default_value = "8")]
+    cell_width: CellWidth,
 
-#[cfg(feature = "jit")]
-fn run_bytecode(code: Vec<u8>) -> std::io::Result<()> {
-    let mut memory = [0u8; MEM_SIZE];
-    let mut buffer = memmap2::MmapOptions::new()
-        .len(code.len())
-        .map_anon()
-        .unwrap();
+    /// Make `#` print the cells up to RADIUS away from the pointer to
+    /// stderr
+    #[clap(long, value_parser, value_name = "RADIUS")]
+    debug_dump: Option<usize>,
 
-    buffer.copy_from_slice(code.as_slice());
+    /// Step through the program in a command line debugger, type help in it
+    /// for its commands
+    #[clap(long, value_parser, conflicts_with_all = &["jit", "emit"])]
+    debug: bool,
 
-    let buffer = buffer.make_exec().unwrap();
+    /// Count how often every token runs on the interpreter and print the
+    /// hottest lines and the loops the optimizer kept to stderr
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &["jit", "emit", "debug"]
+    )]
+    profile: bool,
 
-    unsafe {
-        let code_fn: unsafe extern "C" fn(*mut u8) -> *mut std::io::Error =
-            std::mem::transmute(buffer.as_ptr());
+    /// Print what the JIT compiles the program to instead of running it:
+    /// clif or asm, asm needs the disas feature
+    #[clap(long, value_parser)]
+    emit: Option<Emit>,
 
-        let error = code_fn(memory.as_mut_ptr());
+    /// Stop after running this many tokens, exits with 2 when hit. The JIT
+    /// checks at the end of every loop iteration and before I/O
+    #[clap(long, value_parser, conflicts_with_all = &["debug", "profile"])]
+    max_steps: Option<u64>,
 
-        if !error.is_null() {
-            return Err(*Box::from_raw(error));
-        }
-    }
+    /// Stop after running for this many seconds, exits with 2 when hit
+    #[clap(
+        long,
+        value_parser = parse_seconds,
+        value_name = "SECONDS",
+        conflicts_with_all = &["debug", "profile"]
+    )]
+    timeout: Option<Duration>,
 
-    Ok(())
+    /// Stop when the program writes more than this many bytes, exits with
+    /// 2 when hit
+    #[clap(long, value_parser, conflicts_with_all = &["debug", "profile"])]
+    max_output: Option<u64>,
 }
 
-/// Brainfuck interpreter
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    /// Brainfuck file
-    #[clap(value_parser)]
-    file: PathBuf,
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|_| format!("not a number: {}", s))?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
 
-    /// JIT code instead of interpreting
-    #[clap(short, long, value_parser)]
-    jit: bool,
+fn parse_tape_size(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("the tape needs at least one cell".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("not a number: {}", s)),
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let contents = fs::read_to_string(args.file)
+    let contents = fs::read_to_string(&args.file)
         .expect("Something went wrong reading the file");
-    let tokens = rsbflib::tokenize(&contents);
-    let optimized_tokens = rsbflib::optimize(tokens);
+    let dialect = Dialect {
+        debug_dump: args.debug_dump,
+    };
+    let program = match rsbflib::parse(&contents, dialect) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprint!(
+                    "{}",
+                    error.render(&args.file.display().to_string(), &contents)
+                );
+            }
+            std::process::exit(1);
+        }
+    };
+    let tape = TapeConfig::new(args.tape_size, args.tape_mode, args.cell_width)
+        .unwrap_or_else(|error| {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        });
+
+    // the debugger steps through the source as written, without optimize
+    if args.debug {
+        let debugger = Debugger {
+            eof: args.eof,
+            tape,
+        };
+        // the debugger reads its commands and the program's input from stdin
+        let result = debugger.run(
+            &program,
+            &contents,
+            &mut io::stdin().lock(),
+            &mut io::stdout(),
+            &mut io::stderr(),
+        );
+        if let Err(error) = result {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let program = rsbflib::optimize(program, tape.mode);
+
+    let limits = Limits {
+        steps: args.max_steps,
+        time: args.timeout,
+        output: args.max_output,
+    };
+
+    if let Some(emit) = args.emit {
+        let fuel = limits.needs_fuel();
+        print_listing(emit, &program, args.eof, tape, fuel);
+        return;
+    }
 
-    if args.jit {
+    if args.profile {
+        let profiler = Profiler {
+            eof: args.eof,
+            tape,
+        };
+        let result =
+            profiler.run(&program, &mut io::stdin().lock(), &mut io::stdout());
+        match result {
+            Ok(profile) => eprint!("{}", profile.report(&contents, 10)),
+            Err(error) => {
+                let _ = io::stdout().flush();
+                eprint!(
+                    "{}",
+                    error.render(&args.file.display().to_string(), &contents)
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let engine: Box<dyn Engine> = if args.jit {
         #[cfg(feature = "jit")]
         {
-            let bytecode = codegen::compile(optimized_tokens)
-                .expect("JIT compilation failed");
-            run_bytecode(bytecode).expect("Couldn't run bytecode");
+            Box::new(codegen::Jit {
+                eof: args.eof,
+                tape,
+                limits,
+            })
         }
 
         #[cfg(not(feature = "jit"))]
-        Err("JIT Feature was not enabled at compile time").unwrap()
+        panic!("JIT Feature was not enabled at compile time")
     } else {
-        interpret(optimized_tokens);
+        Box::new(Interpreter {
+            eof: args.eof,
+            tape,
+            limits,
+        })
+    };
+
+    // stdin is buffered internally, locking it once avoids relocking per char
+    let result =
+        engine.run(&program, &mut io::stdin().lock(), &mut io::stdout());
+
+    if let Err(error) = result {
+        // keep the order of output and error the same as in the program
+        let _ = io::stdout().flush();
+        eprint!(
+            "{}",
+            error.render(&args.file.display().to_string(), &contents)
+        );
+        // lets callers tell programs that ran too long from broken ones
+        match error {
+            Error::LimitExceeded(_) => std::process::exit(2),
+            _ => std::process::exit(1),
+        }
     }
 }
+
+// Prints the cranelift IR or disassembly of the JIT compiled program
+#[cfg(feature = "jit")]
+fn print_listing(
+    emit: Emit,
+    program: &rsbflib::ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    fuel: bool,
+) {
+    let listing = match codegen::listing(program, eof, tape, fuel) {
+        Ok(listing) => listing,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    };
+    match emit {
+        Emit::Clif => {
+            for (index, (kind, code_pos)) in listing.sources.iter().enumerate()
+            {
+                println!("; @{:04x}: {:?} at {}", index, kind, code_pos);
+            }
+            println!();
+            println!("; before optimization");
+            println!("{}", listing.clif);
+            println!("; after optimization");
+            print!("{}", listing.optimized_clif);
+        }
+        #[cfg(feature = "disas")]
+        Emit::Asm => print!("{}", listing.asm),
+        #[cfg(not(feature = "disas"))]
+        Emit::Asm => panic!("Disas Feature was not enabled at compile time"),
+    }
+}
+
+#[cfg(not(feature = "jit"))]
+fn print_listing(
+    _emit: Emit,
+    _program: &rsbflib::ir::Block,
+    _eof: EofBehavior,
+    _tape: TapeConfig,
+    _fuel: bool,
+) {
+    panic!("JIT Feature was not enabled at compile time")
+}
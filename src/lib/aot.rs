@@ -0,0 +1,662 @@
+use cranelift::{
+    codegen::{
+        entity::EntityRef,
+        ir::{
+            condcodes::IntCC,
+            types::{I32, I8},
+            AbiParam, Function, InstBuilder, MemFlags, Signature, Type,
+            UserFuncName, Value,
+        },
+        isa::{CallConv, TargetIsa},
+        verify_function, Context,
+    },
+    frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
+};
+use cranelift_module::{
+    default_libcall_names, DataDescription, Linkage, Module,
+};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::io;
+use target_lexicon::Triple;
+
+use crate::{
+    codegen::{
+        self, CONTEXT_DEBUG, CONTEXT_LEN, CONTEXT_OFF_TAPE, CONTEXT_PTR,
+        CONTEXT_READ, CONTEXT_WRITE,
+    },
+    ir, EofBehavior, Error, TapeConfig, TapeMode,
+};
+
+/*
+Ahead of time compiled programs use the same code as the JIT, with a runtime
+written in cranelift IR instead of the rust callbacks in RunContext. The
+runtime reaches everything through the context, so it has no relocations and
+only the entry point depends on how the program is linked.
+*/
+
+// Context slots used by the runtime, after the ones the program uses. The
+// fuel slots stay empty, programs are compiled without fuel
+pub(crate) const CONTEXT_SYS_WRITE: i32 = 8;
+pub(crate) const CONTEXT_SYS_READ: i32 = 9;
+pub(crate) const CONTEXT_SYS_GROW: i32 = 10;
+pub(crate) const CONTEXT_BUFFER: i32 = 11;
+pub(crate) const CONTEXT_BUFFER_LEN: i32 = 12;
+pub(crate) const CONTEXT_SCRATCH: i32 = 13;
+pub(crate) const CONTEXT_SLOTS: usize = 14;
+
+// Bytes of output buffered before they are written
+pub(crate) const BUFFER_SIZE: i64 = 4096;
+// Big enough for a 64 bit number in decimal
+pub(crate) const SCRATCH_SIZE: usize = 32;
+
+// Compiles the program into a relocatable object for triple with a main
+// function that runs it, libc is used for I/O so it has to be linked with a
+// C compiler
+pub fn compile_object(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    triple: Triple,
+) -> Result<Vec<u8>, Error> {
+    let isa = codegen::target_isa(triple, true)?;
+    let builder =
+        ObjectBuilder::new(isa.clone(), "rsbf", default_libcall_names())?;
+    let mut module = ObjectModule::new(builder);
+    let pointer_type = isa.pointer_type();
+
+    let mut functions = vec![];
+    for (name, func) in runtime(program, eof, tape, &*isa)? {
+        let id =
+            module.declare_function(name, Linkage::Local, &func.signature)?;
+        module.define_function(id, &mut Context::for_function(func))?;
+        functions.push(id);
+    }
+
+    // write, read and realloc from libc
+    let mut libc = |name: &str, params: &[Type]| {
+        let sig = signature(&*isa, params, pointer_type);
+        Ok::<_, Error>(module.declare_function(name, Linkage::Import, &sig)?)
+    };
+    let ptr = pointer_type;
+    let write = libc("write", &[I32, ptr, ptr])?;
+    let read = libc("read", &[I32, ptr, ptr])?;
+    let realloc = libc("realloc", &[ptr, ptr])?;
+    let calloc = libc("calloc", &[ptr, ptr])?;
+
+    // grow(address, old_bytes, new_bytes), realloc doesn't need old_bytes
+    let func = build_function(&*isa, &[ptr, ptr, ptr], ptr, false, |rt| {
+        let realloc = module.declare_func_in_func(realloc, rt.builder.func);
+        let args = [rt.params[0], rt.params[2]];
+        let inst = rt.builder.ins().call(realloc, &args);
+        let address = rt.builder.inst_results(inst)[0];
+        rt.builder.ins().return_(&[address]);
+    })?;
+    let grow = module.declare_function(
+        "rsbf_grow",
+        Linkage::Local,
+        &func.signature,
+    )?;
+    module.define_function(grow, &mut Context::for_function(func))?;
+
+    let mut data = |name: &str, size: usize| {
+        let id = module.declare_data(name, Linkage::Local, true, false)?;
+        let mut desc = DataDescription::new();
+        desc.define_zeroinit(size);
+        desc.set_align(16);
+        module.define_data(id, &desc)?;
+        Ok::<_, Error>(id)
+    };
+    let pointer_bytes = pointer_type.bytes() as usize;
+    let context = data("rsbf_context", CONTEXT_SLOTS * pointer_bytes)?;
+    let buffer = data("rsbf_buffer", BUFFER_SIZE as usize)?;
+    let scratch = data("rsbf_scratch", SCRATCH_SIZE)?;
+    let fixed_tape = match tape.mode {
+        TapeMode::Grow => None,
+        _ => Some(data("rsbf_tape", tape.bytes())?),
+    };
+
+    // int main(), fills in the context and runs the program
+    let func = build_function(&*isa, &[], I32, false, |rt| {
+        let symbol =
+            |module: &mut ObjectModule, rt: &mut RuntimeBuilder, id| {
+                let global = module.declare_data_in_func(id, rt.builder.func);
+                rt.builder.ins().symbol_value(pointer_type, global)
+            };
+        rt.context = symbol(&mut module, rt, context);
+        let tape_address = match fixed_tape {
+            Some(fixed_tape) => symbol(&mut module, rt, fixed_tape),
+            None => {
+                let calloc =
+                    module.declare_func_in_func(calloc, rt.builder.func);
+                let size = rt.iconst(tape.size as i64);
+                let bytes = rt.iconst(tape.cell_width.bytes() as i64);
+                let inst = rt.builder.ins().call(calloc, &[size, bytes]);
+                let tape_address = rt.builder.inst_results(inst)[0];
+                rt.return_if(tape_address, IntCC::Equal, 1);
+                tape_address
+            }
+        };
+        rt.store_context(CONTEXT_PTR, tape_address);
+        let size = rt.iconst(tape.size as i64);
+        rt.store_context(CONTEXT_LEN, size);
+        let buffer = symbol(&mut module, rt, buffer);
+        rt.store_context(CONTEXT_BUFFER, buffer);
+        let scratch = symbol(&mut module, rt, scratch);
+        rt.store_context(CONTEXT_SCRATCH, scratch);
+
+        for (index, id) in [
+            (CONTEXT_WRITE, functions[1]),
+            (CONTEXT_READ, functions[2]),
+            (CONTEXT_OFF_TAPE, functions[3]),
+            (CONTEXT_DEBUG, functions[4]),
+            (CONTEXT_SYS_WRITE, write),
+            (CONTEXT_SYS_READ, read),
+            (CONTEXT_SYS_GROW, grow),
+        ] {
+            let func_ref = module.declare_func_in_func(id, rt.builder.func);
+            let address = rt.builder.ins().func_addr(pointer_type, func_ref);
+            rt.store_context(index, address);
+        }
+
+        let program =
+            module.declare_func_in_func(functions[0], rt.builder.func);
+        let inst = rt.builder.ins().call(program, &[rt.context]);
+        let result = rt.builder.inst_results(inst)[0];
+        rt.return_exit_code(result);
+    })?;
+    let main =
+        module.declare_function("main", Linkage::Export, &func.signature)?;
+    module.define_function(main, &mut Context::for_function(func))?;
+
+    module
+        .finish()
+        .emit()
+        .map_err(|err| Error::Io(io::Error::other(err)))
+}
+
+// The program followed by the write, read, off_tape and debug callbacks,
+// none of them have relocations so they can be placed anywhere
+pub(crate) fn runtime(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    isa: &dyn TargetIsa,
+) -> Result<Vec<(&'static str, Function)>, Error> {
+    let ptr = isa.pointer_type();
+    // limits are only supported by the JIT
+    let program = codegen::translate(program, eof, tape, false, isa)?;
+
+    // write(context, value), buffers value until the buffer is full
+    let write = build_function(isa, &[ptr, I8], ptr, true, |rt| {
+        let value = rt.params[1];
+        let buffer = rt.load_context(CONTEXT_BUFFER);
+        let len = rt.load_context(CONTEXT_BUFFER_LEN);
+        let address = rt.builder.ins().iadd(buffer, len);
+        rt.builder.ins().store(MemFlags::new(), value, address, 0);
+        let len = rt.builder.ins().iadd_imm(len, 1);
+        rt.store_context(CONTEXT_BUFFER_LEN, len);
+        let full = rt.builder.ins().icmp_imm(IntCC::Equal, len, BUFFER_SIZE);
+        rt.return_if(full, IntCC::Equal, 0);
+        let result = rt.flush();
+        rt.builder.ins().return_(&[result]);
+    })?;
+
+    // read(context, cell, eof, width), eof and width are known already
+    let read = build_function(isa, &[ptr, ptr, I8, I8], ptr, true, |rt| {
+        let cell = rt.params[1];
+        let cell_type = codegen::cell_type(tape.cell_width);
+        // make sure prompts are visible before blocking on input
+        let result = rt.flush();
+        rt.return_if(result, IntCC::NotEqual, 1);
+        let scratch = rt.load_context(CONTEXT_SCRATCH);
+        let fd = rt.builder.ins().iconst(I32, 0);
+        let one = rt.iconst(1);
+        let read = rt.call_sys(CONTEXT_SYS_READ, &[fd, scratch, one]);
+
+        let got_block = rt.builder.create_block();
+        let eof_block = rt.builder.create_block();
+        let is_got =
+            rt.builder.ins().icmp_imm(IntCC::SignedGreaterThan, read, 0);
+        rt.builder
+            .ins()
+            .brif(is_got, got_block, &[], eof_block, &[]);
+        rt.builder.seal_block(got_block);
+        rt.builder.seal_block(eof_block);
+
+        rt.builder.switch_to_block(got_block);
+        let value = rt.builder.ins().load(I8, MemFlags::new(), scratch, 0);
+        let value = match cell_type {
+            I8 => value,
+            _ => rt.builder.ins().uextend(cell_type, value),
+        };
+        rt.builder.ins().store(MemFlags::new(), value, cell, 0);
+        rt.return_value(0);
+
+        rt.builder.switch_to_block(eof_block);
+        rt.return_if(read, IntCC::SignedLessThan, 1);
+        if let Some(value) = eof.value() {
+            let value = (value & tape.cell_width.max()) as i64;
+            let value = rt.builder.ins().iconst(cell_type, value);
+            rt.builder.ins().store(MemFlags::new(), value, cell, 0);
+        }
+        rt.return_value(0);
+    })?;
+
+    // off_tape(context, index, line, col, offset)
+    let off_tape = build_function(isa, &[ptr; 5], ptr, true, |rt| {
+        let (index, line, col) = (rt.params[1], rt.params[2], rt.params[3]);
+        if tape.mode == TapeMode::Grow {
+            let grow_block = rt.builder.create_block();
+            let error_block = rt.builder.create_block();
+            let is_left =
+                rt.builder.ins().icmp_imm(IntCC::SignedLessThan, index, 0);
+            rt.builder
+                .ins()
+                .brif(is_left, error_block, &[], grow_block, &[]);
+            rt.builder.seal_block(grow_block);
+            rt.builder.seal_block(error_block);
+            rt.builder.switch_to_block(grow_block);
+            rt.grow(index, tape);
+            rt.builder.switch_to_block(error_block);
+        }
+        // errors writing to stderr can't be reported anywhere
+        rt.flush();
+        let message = format!("error: {} ", tape.mode.off_tape_message());
+        rt.write_bytes(message.as_bytes());
+        rt.write_number(line);
+        rt.write_bytes(b":");
+        rt.write_number(col);
+        rt.write_bytes(b"\n");
+        rt.return_value(1);
+    })?;
+
+    // debug(context, pointer, radius), compiled programs ignore debug dumps
+    let debug = build_function(isa, &[ptr; 3], ptr, true, |rt| {
+        rt.return_value(0);
+    })?;
+
+    Ok(vec![
+        ("rsbf_program", program),
+        ("rsbf_write", write),
+        ("rsbf_read", read),
+        ("rsbf_off_tape", off_tape),
+        ("rsbf_debug", debug),
+    ])
+}
+
+// start(context, program) -> i32, runs the program at the given address with
+// a context that is filled in already and returns the exit code
+pub(crate) fn start(isa: &dyn TargetIsa) -> Result<Function, Error> {
+    let ptr = isa.pointer_type();
+    build_function(isa, &[ptr, ptr], I32, true, |rt| {
+        let sig = signature(isa, &[ptr], ptr);
+        let sig = rt.builder.import_signature(sig);
+        let program = rt.params[1];
+        let inst = rt.builder.ins().call_indirect(sig, program, &[rt.context]);
+        let result = rt.builder.inst_results(inst)[0];
+        rt.return_exit_code(result);
+    })
+}
+
+fn signature(isa: &dyn TargetIsa, params: &[Type], returns: Type) -> Signature {
+    let mut sig = Signature::new(CallConv::triple_default(isa.triple()));
+    for param in params {
+        sig.params.push(abi_param(*param));
+    }
+    sig.returns.push(abi_param(returns));
+    sig
+}
+
+// I32 is only used for C ints, some ABIs want those extended to a register
+// by the caller or callee
+fn abi_param(value_type: Type) -> AbiParam {
+    match value_type {
+        I32 => AbiParam::new(I32).sext(),
+        _ => AbiParam::new(value_type),
+    }
+}
+
+// Builds and verifies a function returning a single value, with context the
+// first parameter is the context pointer
+fn build_function(
+    isa: &dyn TargetIsa,
+    params: &[Type],
+    returns: Type,
+    context: bool,
+    body: impl FnOnce(&mut RuntimeBuilder),
+) -> Result<Function, Error> {
+    let sig = signature(isa, params, returns);
+    let mut func = Function::with_name_signature(UserFuncName::default(), sig);
+    let mut func_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let params = builder.block_params(block).to_vec();
+
+    let mut rt = RuntimeBuilder {
+        builder,
+        pointer_type: isa.pointer_type(),
+        call_conv: CallConv::triple_default(isa.triple()),
+        // without a context parameter body has to set it
+        context: params
+            .first()
+            .copied()
+            .filter(|_| context)
+            .unwrap_or(Value::new(0)),
+        params,
+        variables: 0,
+    };
+    body(&mut rt);
+    rt.builder.finalize();
+
+    verify_function(&func, isa)?;
+    Ok(func)
+}
+
+// Builds a runtime function, everything is reached through the context
+struct RuntimeBuilder<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer_type: Type,
+    call_conv: CallConv,
+    context: Value,
+    params: Vec<Value>,
+    // counts variables declared by loops
+    variables: usize,
+}
+
+impl<'a> RuntimeBuilder<'a> {
+    fn iconst(&mut self, value: i64) -> Value {
+        self.builder.ins().iconst(self.pointer_type, value)
+    }
+
+    fn load_context(&mut self, index: i32) -> Value {
+        let offset = index * self.pointer_type.bytes() as i32;
+        self.builder.ins().load(
+            self.pointer_type,
+            MemFlags::trusted(),
+            self.context,
+            offset,
+        )
+    }
+
+    fn store_context(&mut self, index: i32, value: Value) {
+        let offset = index * self.pointer_type.bytes() as i32;
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            value,
+            self.context,
+            offset,
+        );
+    }
+
+    fn return_value(&mut self, value: i64) {
+        let return_type = self.builder.func.signature.returns[0].value_type;
+        let value = self.builder.ins().iconst(return_type, value);
+        self.builder.ins().return_(&[value]);
+    }
+
+    // Flushes the output and returns the exit code of a program that
+    // returned result
+    fn return_exit_code(&mut self, result: Value) {
+        // the runtime already reported why the program stopped
+        self.return_if(result, IntCC::NotEqual, 1);
+        let result = self.flush();
+        self.return_if(result, IntCC::NotEqual, 1);
+        self.return_value(0);
+    }
+
+    // Returns value if cond holds when comparing value to zero, the
+    // function continues in a new block otherwise
+    fn return_if(&mut self, value: Value, cond: IntCC, result: i64) {
+        let return_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+        let cmp = self.builder.ins().icmp_imm(cond, value, 0);
+        self.builder
+            .ins()
+            .brif(cmp, return_block, &[], after_block, &[]);
+        self.builder.seal_block(return_block);
+        self.builder.switch_to_block(return_block);
+        self.return_value(result);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+    }
+
+    // Calls the system function at index of the context
+    fn call_sys(&mut self, index: i32, args: &[Value]) -> Value {
+        let mut sig = Signature::new(self.call_conv);
+        for arg in args {
+            let value_type = self.builder.func.dfg.value_type(*arg);
+            sig.params.push(abi_param(value_type));
+        }
+        sig.returns.push(AbiParam::new(self.pointer_type));
+        let sig = self.builder.import_signature(sig);
+        let callee = self.load_context(index);
+        let inst = self.builder.ins().call_indirect(sig, callee, args);
+        self.builder.inst_results(inst)[0]
+    }
+
+    fn new_variable(&mut self) -> Variable {
+        // parameters aren't variables, so any index is free
+        let variable = Variable::new(self.variables);
+        self.variables += 1;
+        self.builder.declare_var(variable, self.pointer_type);
+        variable
+    }
+
+    // Writes len bytes at address to fd, returns 1 if that failed
+    fn write_all(&mut self, fd: i64, address: Value, len: Value) -> Value {
+        let written = self.new_variable();
+        let zero = self.iconst(0);
+        self.builder.def_var(written, zero);
+
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+        self.builder
+            .append_block_param(after_block, self.pointer_type);
+        self.builder.ins().jump(header_block, &[]);
+
+        self.builder.switch_to_block(header_block);
+        let done = self.builder.use_var(written);
+        let is_done = self.builder.ins().icmp(
+            IntCC::UnsignedGreaterThanOrEqual,
+            done,
+            len,
+        );
+        let zero = self.iconst(0);
+        self.builder
+            .ins()
+            .brif(is_done, after_block, &[zero], body_block, &[]);
+
+        self.builder.seal_block(body_block);
+        self.builder.switch_to_block(body_block);
+        let fd = self.builder.ins().iconst(I32, fd);
+        let start = self.builder.ins().iadd(address, done);
+        let left = self.builder.ins().isub(len, done);
+        let result = self.call_sys(CONTEXT_SYS_WRITE, &[fd, start, left]);
+        let done = self.builder.ins().iadd(done, result);
+        self.builder.def_var(written, done);
+        let failed = self.builder.ins().icmp_imm(
+            IntCC::SignedLessThanOrEqual,
+            result,
+            0,
+        );
+        let one = self.iconst(1);
+        self.builder
+            .ins()
+            .brif(failed, after_block, &[one], header_block, &[]);
+
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+        self.builder.block_params(after_block)[0]
+    }
+
+    // Writes the buffered output to stdout, returns 1 if that failed
+    fn flush(&mut self) -> Value {
+        let buffer = self.load_context(CONTEXT_BUFFER);
+        let len = self.load_context(CONTEXT_BUFFER_LEN);
+        let result = self.write_all(1, buffer, len);
+        let zero = self.iconst(0);
+        self.store_context(CONTEXT_BUFFER_LEN, zero);
+        result
+    }
+
+    // Writes bytes to stderr through the scratch space
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(SCRATCH_SIZE) {
+            let scratch = self.load_context(CONTEXT_SCRATCH);
+            for (offset, byte) in chunk.iter().enumerate() {
+                let value = self.builder.ins().iconst(I8, *byte as i64);
+                self.builder.ins().store(
+                    MemFlags::new(),
+                    value,
+                    scratch,
+                    offset as i32,
+                );
+            }
+            let len = self.iconst(chunk.len() as i64);
+            self.write_all(2, scratch, len);
+        }
+    }
+
+    // Writes number in decimal to stderr, digits are put at the end of the
+    // scratch space from right to left
+    fn write_number(&mut self, number: Value) {
+        let scratch = self.load_context(CONTEXT_SCRATCH);
+        let end = self.builder.ins().iadd_imm(scratch, SCRATCH_SIZE as i64);
+        let rest = self.new_variable();
+        let start = self.new_variable();
+        self.builder.def_var(rest, number);
+        self.builder.def_var(start, end);
+
+        let body_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+        self.builder.ins().jump(body_block, &[]);
+
+        self.builder.switch_to_block(body_block);
+        let number = self.builder.use_var(rest);
+        let digit = self.builder.ins().urem_imm(number, 10);
+        let digit = self.builder.ins().iadd_imm(digit, b'0' as i64);
+        let digit = self.builder.ins().ireduce(I8, digit);
+        let address = self.builder.use_var(start);
+        let address = self.builder.ins().iadd_imm(address, -1);
+        self.builder.ins().store(MemFlags::new(), digit, address, 0);
+        self.builder.def_var(start, address);
+        let number = self.builder.ins().udiv_imm(number, 10);
+        self.builder.def_var(rest, number);
+        self.builder
+            .ins()
+            .brif(number, body_block, &[], after_block, &[]);
+
+        self.builder.seal_block(body_block);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+        let address = self.builder.use_var(start);
+        let len = self.builder.ins().isub(end, address);
+        self.write_all(2, address, len);
+    }
+
+    // Grows the tape so index is on it and returns, new cells are zeroed
+    fn grow(&mut self, index: Value, tape: TapeConfig) {
+        let shift = tape.cell_width.bytes().trailing_zeros() as i64;
+        let len = self.load_context(CONTEXT_LEN);
+        let doubled = self.builder.ins().ishl_imm(len, 1);
+        let needed = self.builder.ins().iadd_imm(index, 1);
+        let is_enough = self.builder.ins().icmp(
+            IntCC::UnsignedGreaterThanOrEqual,
+            doubled,
+            needed,
+        );
+        let new_len = self.builder.ins().select(is_enough, doubled, needed);
+
+        let old_bytes = self.builder.ins().ishl_imm(len, shift);
+        let new_bytes = self.builder.ins().ishl_imm(new_len, shift);
+        let address = self.load_context(CONTEXT_PTR);
+        let address =
+            self.call_sys(CONTEXT_SYS_GROW, &[address, old_bytes, new_bytes]);
+        self.return_if(address, IntCC::Equal, 1);
+
+        // zero the new cells
+        let cursor = self.new_variable();
+        let start = self.builder.ins().iadd(address, old_bytes);
+        let end = self.builder.ins().iadd(address, new_bytes);
+        self.builder.def_var(cursor, start);
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+        self.builder.ins().jump(header_block, &[]);
+
+        self.builder.switch_to_block(header_block);
+        let current = self.builder.use_var(cursor);
+        let is_done = self.builder.ins().icmp(
+            IntCC::UnsignedGreaterThanOrEqual,
+            current,
+            end,
+        );
+        self.builder
+            .ins()
+            .brif(is_done, after_block, &[], body_block, &[]);
+
+        self.builder.seal_block(body_block);
+        self.builder.switch_to_block(body_block);
+        let zero = self.builder.ins().iconst(I8, 0);
+        self.builder.ins().store(MemFlags::new(), zero, current, 0);
+        let next = self.builder.ins().iadd_imm(current, 1);
+        self.builder.def_var(cursor, next);
+        self.builder.ins().jump(header_block, &[]);
+
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+        self.store_context(CONTEXT_PTR, address);
+        self.store_context(CONTEXT_LEN, new_len);
+        self.return_value(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{optimize, parse, CellWidth, Dialect, TapeMode};
+
+    #[test]
+    fn object_for_every_enabled_triple() {
+        let program = parse(
+            "+[>,.<-]#",
+            Dialect {
+                debug_dump: Some(2),
+            },
+        );
+        let program = optimize(program.unwrap(), TapeMode::Wrap);
+        let triples = [
+            "x86_64-unknown-linux-gnu",
+            "aarch64-unknown-linux-gnu",
+            "riscv64gc-unknown-linux-gnu",
+            "s390x-unknown-linux-gnu",
+        ];
+        let mut compiled = 0;
+        for triple in triples {
+            for mode in [TapeMode::Wrap, TapeMode::Grow] {
+                let tape = TapeConfig::new(16, mode, CellWidth::U16).unwrap();
+                let triple = Triple::from_str(triple).unwrap();
+                match compile_object(&program, EofBehavior::Zero, tape, triple)
+                {
+                    Err(Error::DisabledIsa(_)) => continue,
+                    result => {
+                        let object = result.unwrap();
+                        assert_eq!(&object[..4], b"\x7fELF");
+                        compiled += 1;
+                    }
+                }
+            }
+        }
+        // the backend of the host is always there
+        assert!(compiled > 0);
+    }
+}
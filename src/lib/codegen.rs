@@ -3,22 +3,30 @@ use cranelift::{
         control::ControlPlane,
         entity::EntityRef,
         ir::{
-            condcodes::IntCC, types::I8, AbiParam, Function, InstBuilder,
-            MemFlags, Signature, UserFuncName,
+            condcodes::IntCC,
+            types::{I16, I32, I8},
+            AbiParam, Block, Function, InstBuilder, MemFlags, SigRef,
+            Signature, SourceLoc, Type, UserFuncName, Value,
         },
-        isa::{self, CallConv},
+        isa::{self, CallConv, OwnedTargetIsa, TargetIsa},
         settings::{self, Configurable},
         verify_function, Context,
     },
     frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
 };
 use std::{
-    error::Error,
     io::{Read, Write},
+    time::Instant,
 };
 use target_lexicon::Triple;
 
-use crate::{BracketState, Token, TokenKind};
+use crate::{
+    debug_dump,
+    engine::with_output_limit,
+    ir::{self, Node},
+    BracketState, CellWidth, CodePos, Engine, EofBehavior, Error, Limit,
+    Limits, RunStats, TapeConfig, TapeMode, Token, TokenKind,
+};
 
 /*
 Thanks a LOT! to https://github.com/Rodrigodd
@@ -27,26 +35,345 @@ Almost all of this code is from
 https://github.com/Rodrigodd/bf-compiler/blob/master/cranelift-jit/src/main.rs
 */
 
-pub fn compile(instructions: Vec<Token>) -> Result<Vec<u8>, Box<dyn Error>> {
+// Passed to the compiled function as a context pointer, the generated code
+// reads the tape and calls the callbacks through it. The fields up to
+// refuel are pointer sized and read by the generated code, see
+// FunctionTranslator::load_context, len is in cells and not bytes
+#[repr(C)]
+pub struct RunContext<'a> {
+    ptr: *mut u8,
+    len: usize,
+    write: unsafe extern "C" fn(*mut RunContext<'a>, u8) -> *mut Error,
+    read: unsafe extern "C" fn(
+        *mut RunContext<'a>,
+        *mut u8,
+        EofBehavior,
+        CellWidth,
+    ) -> *mut Error,
+    // grow in TapeMode::Grow, pointer_off_tape in TapeMode::Strict and
+    // out_of_bounds otherwise
+    off_tape: unsafe extern "C" fn(
+        *mut RunContext<'a>,
+        usize,
+        usize,
+        usize,
+        usize,
+    ) -> *mut Error,
+    debug:
+        unsafe extern "C" fn(*mut RunContext<'a>, usize, usize) -> *mut Error,
+    // tokens the generated code may run before it calls refuel, only code
+    // compiled with fuel uses these
+    fuel: isize,
+    refuel: unsafe extern "C" fn(*mut RunContext<'a>, isize) -> *mut Error,
+    limits: Limits,
+    // fuel handed out so far
+    fueled: u64,
+    start: Instant,
+    cells: Vec<u8>,
+    cell_bytes: usize,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> RunContext<'a> {
+    pub fn new(
+        tape: TapeConfig,
+        limits: Limits,
+        input: &'a mut dyn Read,
+        output: &'a mut dyn Write,
+    ) -> Self {
+        let cell_bytes = tape.cell_width.bytes();
+        let mut cells = vec![0; tape.bytes()];
+        RunContext {
+            ptr: cells.as_mut_ptr(),
+            len: tape.size,
+            write,
+            read,
+            off_tape: match tape.mode {
+                TapeMode::Grow => grow,
+                TapeMode::Strict => pointer_off_tape as _,
+                _ => out_of_bounds as _,
+            },
+            debug,
+            fuel: 0,
+            refuel,
+            limits,
+            fueled: 0,
+            start: Instant::now(),
+            cells,
+            cell_bytes,
+            input,
+            output,
+        }
+    }
+}
+
+// Indices of the fields of RunContext read by the generated code
+pub(crate) const CONTEXT_PTR: i32 = 0;
+pub(crate) const CONTEXT_LEN: i32 = 1;
+pub(crate) const CONTEXT_WRITE: i32 = 2;
+pub(crate) const CONTEXT_READ: i32 = 3;
+pub(crate) const CONTEXT_OFF_TAPE: i32 = 4;
+pub(crate) const CONTEXT_DEBUG: i32 = 5;
+pub(crate) const CONTEXT_FUEL: i32 = 6;
+pub(crate) const CONTEXT_REFUEL: i32 = 7;
+
+// Most fuel handed out at once, the time limit is checked in between
+const FUEL_CHUNK: u64 = 1 << 20;
+
+/// Runs code returned by compile, output is flushed once the program ends
+///
+/// # Safety
+///
+/// code has to come from compile with the same TapeConfig that was used to
+/// create context, and with fuel if its limits need fuel
+pub unsafe fn run(code: &[u8], context: &mut RunContext) -> Result<(), Error> {
+    let mut buffer = memmap2::MmapOptions::new().len(code.len()).map_anon()?;
+    buffer.copy_from_slice(code);
+    let buffer = buffer.make_exec()?;
+
+    let code_fn: unsafe extern "C" fn(*mut RunContext) -> *mut Error =
+        std::mem::transmute(buffer.as_ptr());
+
+    let error = code_fn(context);
+    if !error.is_null() {
+        return Err(*Box::from_raw(error));
+    }
+
+    context.output.flush()?;
+    Ok(())
+}
+
+// Compiles the program and runs the machine code directly
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Jit {
+    pub eof: EofBehavior,
+    pub tape: TapeConfig,
+    pub limits: Limits,
+}
+
+impl Engine for Jit {
+    fn run(
+        &self,
+        program: &ir::Block,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<RunStats, Error> {
+        let start = Instant::now();
+        let fuel = self.limits.needs_fuel();
+        let code = compile(program, self.eof, self.tape, fuel)?;
+        let compile_time = start.elapsed();
+
+        let start = Instant::now();
+        with_output_limit(output, self.limits.output, |output| {
+            let mut context =
+                RunContext::new(self.tape, self.limits, input, output);
+            // the code was just compiled with the same tape
+            unsafe { run(&code, &mut context) }
+        })?;
+        Ok(RunStats {
+            compile_time,
+            run_time: start.elapsed(),
+        })
+    }
+}
+
+// Compiles the program into a function taking a pointer to a RunContext and
+// returning a pointer to a boxed Error, or null if it ran successfully. With
+// fuel the function counts the tokens it runs to enforce the RunContext's
+// limits, checking them at the end of every loop iteration
+pub fn compile(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    fuel: bool,
+) -> Result<Vec<u8>, Error> {
+    let isa = host_isa(false)?;
+    let func = translate(program, eof, tape, fuel, &*isa)?;
+
+    let mut ctx = Context::for_function(func);
+    let code = ctx
+        .compile(&*isa, &mut ControlPlane::default())
+        .map_err(|err| err.inner)?;
+
+    let code = code.code_buffer().to_vec();
+
+    Ok(code)
+}
+
+// The code compile generates in a readable form
+pub struct Listing {
+    // cranelift IR as translated and after cranelift optimized it
+    pub clif: String,
+    pub optimized_clif: String,
+    // the token and position each source location (@ in clif) stands for
+    pub sources: Vec<(TokenKind, CodePos)>,
+    // disassembly of the machine code, every run of instructions starts
+    // with a comment naming the token it came from
+    #[cfg(feature = "disas")]
+    pub asm: String,
+}
+
+// Compiles the program like compile, but returns what it compiled to
+pub fn listing(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    fuel: bool,
+) -> Result<Listing, Error> {
+    let isa = host_isa(false)?;
+    let (func, sources) =
+        translate_with_sources(program, eof, tape, fuel, &*isa)?;
+    let clif = func.display().to_string();
+
+    let mut ctx = Context::for_function(func);
+    let code = ctx
+        .compile(&*isa, &mut ControlPlane::default())
+        .map_err(|err| err.inner)?;
+    #[cfg(feature = "disas")]
+    let asm = disassemble(
+        code.code_buffer(),
+        code.buffer.get_srclocs_sorted(),
+        &sources,
+    )?;
+    #[cfg(not(feature = "disas"))]
+    let _ = code;
+
+    Ok(Listing {
+        #[cfg(feature = "disas")]
+        asm,
+        clif,
+        optimized_clif: ctx.func.display().to_string(),
+        sources,
+    })
+}
+
+// Disassembles code for the host, sources are the tokens the source
+// locations of the code index
+#[cfg(feature = "disas")]
+fn disassemble(
+    code: &[u8],
+    srclocs: &[cranelift::codegen::MachSrcLoc<cranelift::codegen::Final>],
+    sources: &[(TokenKind, CodePos)],
+) -> Result<String, Error> {
+    use capstone::prelude::*;
+    use target_lexicon::Architecture;
+
+    let triple = Triple::host();
+    let capstone = match triple.architecture {
+        Architecture::X86_64 => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .syntax(arch::x86::ArchSyntax::Intel)
+            .build(),
+        Architecture::Aarch64(_) => Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .build(),
+        _ => return Err(Error::UnsupportedIsa(triple.to_string())),
+    }
+    .map_err(|err| std::io::Error::other(err.to_string()))?;
+    let instructions = capstone
+        .disasm_all(code, 0)
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+    let mut result = String::new();
+    let mut last_source = None;
+    for instruction in instructions.iter() {
+        let address = instruction.address() as u32;
+        let source = srclocs
+            .iter()
+            .find(|srcloc| srcloc.start <= address && address < srcloc.end)
+            .filter(|srcloc| !srcloc.loc.is_default())
+            .map(|srcloc| srcloc.loc.bits() as usize);
+        if source != last_source || result.is_empty() {
+            result += &match source {
+                Some(index) => {
+                    let (kind, code_pos) = &sources[index];
+                    format!("; {:?} at {}\n", kind, code_pos)
+                }
+                None => "; no token\n".to_string(),
+            };
+            last_source = source;
+        }
+        result += &format!(
+            "  {:6x}: {} {}\n",
+            address,
+            instruction.mnemonic().unwrap_or(""),
+            instruction.op_str().unwrap_or("")
+        );
+    }
+    Ok(result)
+}
+
+// Looks up the ISA of the host, pic is needed for code that gets linked
+pub(crate) fn host_isa(pic: bool) -> Result<OwnedTargetIsa, Error> {
+    target_isa(Triple::host(), pic)
+}
+
+// Looks up the ISA of triple, backends other than the host's have to be
+// enabled with the feature of the same name
+pub(crate) fn target_isa(
+    triple: Triple,
+    pic: bool,
+) -> Result<OwnedTargetIsa, Error> {
     // possible settings: https://docs.rs/cranelift-codegen/latest/src/cranelift_codegen/opt/rustwide/target/x86_64-unknown-linux-gnu/debug/build/cranelift-codegen-b5deaeb0cd154533/out/settings.rs.html#490-664
     let mut builder = settings::builder();
     builder.set("opt_level", "speed").unwrap();
+    if pic {
+        builder.set("is_pic", "true").unwrap();
+    }
     // issue: https://github.com/bytecodealliance/wasmtime/issues/1148
     // builder.set("preserve_frame_pointers", "false").unwrap();
     // builder.set("use_egraphs", "true").unwrap();
 
     let flags = settings::Flags::new(builder);
 
-    let isa = match isa::lookup(Triple::host()) {
-        Err(_) => panic!("x86_64 ISA is not avaliable"),
-        Ok(isa_builder) => isa_builder.finish(flags).unwrap(),
-    };
+    match isa::lookup(triple.clone()) {
+        Err(isa::LookupError::SupportDisabled) => {
+            Err(Error::DisabledIsa(triple.to_string()))
+        }
+        Err(_) => Err(Error::UnsupportedIsa(triple.to_string())),
+        Ok(isa_builder) => Ok(isa_builder.finish(flags)?),
+    }
+}
 
+// Cranelift type of a single cell
+pub(crate) fn cell_type(cell_width: CellWidth) -> Type {
+    match cell_width {
+        CellWidth::U8 => I8,
+        CellWidth::U16 => I16,
+        CellWidth::U32 => I32,
+    }
+}
+
+// Translates the program into the function compile compiles, it only calls
+// other code through the function pointers in RunContext
+pub(crate) fn translate(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    fuel: bool,
+    isa: &dyn TargetIsa,
+) -> Result<Function, Error> {
+    Ok(translate_with_sources(program, eof, tape, fuel, isa)?.0)
+}
+
+// Like translate, also returns the token every source location of the
+// function indexes
+fn translate_with_sources(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    fuel: bool,
+    isa: &dyn TargetIsa,
+) -> Result<(Function, Vec<(TokenKind, CodePos)>), Error> {
+    tape.validate()?;
     let pointer_type = isa.pointer_type();
 
     let call_conv = CallConv::triple_default(isa.triple());
 
-    // get memory address parameter, and return pointer to io::Error
+    // get context address parameter, and return pointer to Error
     let mut sig = Signature::new(call_conv);
     sig.params.push(AbiParam::new(pointer_type));
     sig.returns.push(AbiParam::new(pointer_type));
@@ -58,6 +385,15 @@ pub fn compile(instructions: Vec<Token>) -> Result<Vec<u8>, Box<dyn Error>> {
 
     let pointer = Variable::new(0);
     builder.declare_var(pointer, pointer_type);
+    let memory_address = Variable::new(1);
+    builder.declare_var(memory_address, pointer_type);
+    let tape_len = Variable::new(2);
+    builder.declare_var(tape_len, pointer_type);
+    let fuel = fuel.then(|| {
+        let fuel = Variable::new(3);
+        builder.declare_var(fuel, pointer_type);
+        fuel
+    });
 
     let exit_block = builder.create_block();
     builder.append_block_param(exit_block, pointer_type);
@@ -68,301 +404,782 @@ pub fn compile(instructions: Vec<Token>) -> Result<Vec<u8>, Box<dyn Error>> {
     builder.append_block_params_for_function_params(block);
     builder.switch_to_block(block);
 
-    let memory_address = builder.block_params(block)[0];
+    let context_address = builder.block_params(block)[0];
 
-    let zero_byte = builder.ins().iconst(I8, 0);
+    let cell_type = cell_type(tape.cell_width);
+    let zero_cell = builder.ins().iconst(cell_type, 0);
     let zero = builder.ins().iconst(pointer_type, 0);
     builder.def_var(pointer, zero);
 
     let mem_flags = MemFlags::new(); //.with_notrap().with_heap();
 
-    let (write_sig, write_address) = {
-        let mut write_sig = Signature::new(call_conv);
-        write_sig.params.push(AbiParam::new(I8));
-        write_sig.returns.push(AbiParam::new(pointer_type));
-        let write_sig = builder.import_signature(write_sig);
-
-        let write_address = write as *const () as i64;
-        let write_address = builder.ins().iconst(pointer_type, write_address);
-        (write_sig, write_address)
+    // every callback takes the context first
+    let mut callback_sig = |params: &[Type]| {
+        let mut sig = Signature::new(call_conv);
+        sig.params.push(AbiParam::new(pointer_type));
+        for param in params {
+            sig.params.push(AbiParam::new(*param));
+        }
+        sig.returns.push(AbiParam::new(pointer_type));
+        builder.import_signature(sig)
+    };
+    let write_sig = callback_sig(&[I8]);
+    let read_sig = callback_sig(&[pointer_type, I8, I8]);
+    // called with the index and the position of the token accessing it
+    let off_tape_sig = callback_sig(&[pointer_type; 4]);
+    // called with the pointer and the radius of the dump
+    let debug_sig = callback_sig(&[pointer_type; 2]);
+    // called with the fuel that is left
+    let refuel_sig = callback_sig(&[pointer_type]);
+
+    let eof_value = builder.ins().iconst(I8, eof as i64);
+    let width_value = builder.ins().iconst(I8, tape.cell_width as i64);
+
+    let mut translator = FunctionTranslator {
+        builder,
+        pointer,
+        pointer_type,
+        memory_address,
+        context_address,
+        tape_len,
+        tape,
+        mem_flags,
+        exit_block,
+        cell_type,
+        zero_cell,
+        write_sig,
+        read_sig,
+        eof_value,
+        width_value,
+        off_tape_sig,
+        debug_sig,
+        fuel,
+        cost: 0,
+        refuel_sig,
+        sources: vec![],
     };
+    translator.load_tape();
+    if let Some(fuel) = fuel {
+        let fuel_value = translator.load_context(CONTEXT_FUEL);
+        translator.builder.def_var(fuel, fuel_value);
+    }
+    translator.translate_block(program);
+    translator.charge();
+    let mut builder = translator.builder;
+    let sources = translator.sources;
 
-    let (read_sig, read_address) = {
-        let mut read_sig = Signature::new(call_conv);
-        read_sig.params.push(AbiParam::new(pointer_type));
-        read_sig.returns.push(AbiParam::new(pointer_type));
-        let read_sig = builder.import_signature(read_sig);
+    builder.set_srcloc(SourceLoc::default());
+    builder.ins().return_(&[zero]);
 
-        let read_address = read as *const () as i64;
-        let read_address = builder.ins().iconst(pointer_type, read_address);
-        (read_sig, read_address)
-    };
+    builder.switch_to_block(exit_block);
+    builder.seal_block(exit_block);
 
-    let mut stack = Vec::new();
+    let result = builder.block_params(exit_block)[0];
+    builder.ins().return_(&[result]);
 
-    for (_, instr) in instructions.into_iter().enumerate() {
-        match instr.kind {
-            TokenKind::ValMod(n) => {
-                let n = n as i64;
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                let cell_value =
-                    builder.ins().load(I8, mem_flags, cell_address, 0);
-                let cell_value = builder.ins().iadd_imm(cell_value, n as i64);
-                builder.ins().store(mem_flags, cell_value, cell_address, 0);
-            }
-            TokenKind::PosMod(n) => {
-                let n = n as i64;
-                let pointer_value = builder.use_var(pointer);
-                let pointer_plus = builder.ins().iadd_imm(pointer_value, n);
-
-                let pointer_value = if n > 0 {
-                    let wrapped =
-                        builder.ins().iadd_imm(pointer_value, n - 30_000);
-                    let cmp = builder.ins().icmp_imm(
-                        IntCC::SignedLessThan,
-                        pointer_plus,
-                        30_000,
-                    );
-                    builder.ins().select(cmp, pointer_plus, wrapped)
-                } else {
-                    let wrapped =
-                        builder.ins().iadd_imm(pointer_value, n + 30_000);
-                    let cmp = builder.ins().icmp_imm(
-                        IntCC::SignedLessThan,
-                        pointer_plus,
-                        0,
-                    );
-                    builder.ins().select(cmp, wrapped, pointer_plus)
-                };
+    builder.finalize();
 
-                builder.def_var(pointer, pointer_value);
-            }
-            TokenKind::Output => {
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                let cell_value =
-                    builder.ins().load(I8, mem_flags, cell_address, 0);
+    verify_function(&func, isa)?;
 
-                let inst = builder.ins().call_indirect(
-                    write_sig,
-                    write_address,
-                    &[cell_value],
-                );
-                let result = builder.inst_results(inst)[0];
+    Ok((func, sources))
+}
 
-                let after_block = builder.create_block();
+// Holds everything needed while translating the program tree into a function
+struct FunctionTranslator<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer: Variable,
+    pointer_type: Type,
+    // context.ptr and context.len, both change when the tape grows
+    memory_address: Variable,
+    context_address: Value,
+    tape_len: Variable,
+    tape: TapeConfig,
+    mem_flags: MemFlags,
+    exit_block: Block,
+    cell_type: Type,
+    zero_cell: Value,
+    write_sig: SigRef,
+    read_sig: SigRef,
+    eof_value: Value,
+    width_value: Value,
+    off_tape_sig: SigRef,
+    debug_sig: SigRef,
+    // fuel left, None if the function isn't compiled with fuel
+    fuel: Option<Variable>,
+    // tokens translated since the last charge
+    cost: i64,
+    refuel_sig: SigRef,
+    // the token and position each source location stands for
+    sources: Vec<(TokenKind, CodePos)>,
+}
 
-                builder.ins().brif(
-                    result,
-                    exit_block,
-                    &[result],
-                    after_block,
-                    &[],
-                );
-                // builder.ins().brnz(result, exit_block, &[result]);
-                // builder.ins().jump(after_block, &[]);
+impl FunctionTranslator<'_> {
+    // Marks the instructions added from now on as coming from kind
+    fn set_source(&mut self, kind: TokenKind, code_pos: &CodePos) {
+        let srcloc = SourceLoc::new(self.sources.len() as u32);
+        self.builder.set_srcloc(srcloc);
+        self.sources.push((kind, code_pos.clone()));
+    }
 
-                builder.seal_block(after_block);
-                builder.switch_to_block(after_block);
-            }
-            TokenKind::Input => {
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-
-                let inst = builder.ins().call_indirect(
-                    read_sig,
-                    read_address,
-                    &[cell_address],
-                );
-                let result = builder.inst_results(inst)[0];
+    // Loads the field at index of RunContext
+    fn load_context(&mut self, index: i32) -> Value {
+        let pointer_type = self.pointer_type;
+        self.builder.ins().load(
+            pointer_type,
+            MemFlags::trusted(),
+            self.context_address,
+            index * pointer_type.bytes() as i32,
+        )
+    }
 
-                let after_block = builder.create_block();
+    // Loads the tape address and length, again after the tape grows
+    fn load_tape(&mut self) {
+        let memory_value = self.load_context(CONTEXT_PTR);
+        self.builder.def_var(self.memory_address, memory_value);
+        let len_value = self.load_context(CONTEXT_LEN);
+        self.builder.def_var(self.tape_len, len_value);
+    }
 
-                builder.ins().brif(
-                    result,
-                    exit_block,
-                    &[result],
-                    after_block,
-                    &[],
-                );
-                // builder.ins().brnz(result, exit_block, &[result]);
-                // builder.ins().jump(after_block, &[]);
+    // Calls the callback at index of RunContext with the context and args
+    fn call_context(&mut self, index: i32, sig: SigRef, args: &[Value]) {
+        let callback = self.load_context(index);
+        let mut call_args = vec![self.context_address];
+        call_args.extend_from_slice(args);
+        let inst = self.builder.ins().call_indirect(sig, callback, &call_args);
+        let result = self.builder.inst_results(inst)[0];
+        self.check_callback_result(result);
+    }
 
-                builder.seal_block(after_block);
-                builder.switch_to_block(after_block);
+    fn translate_block(&mut self, block: &ir::Block) {
+        for node in block {
+            match node {
+                Node::Token(token) => self.translate_token(token),
+                Node::Loop(inner) => self.translate_loop(inner),
             }
-            TokenKind::Bracket(BracketState::Open) => {
-                let inner_block = builder.create_block();
-                let after_block = builder.create_block();
+        }
+    }
 
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                let cell_value =
-                    builder.ins().load(I8, mem_flags, cell_address, 0);
+    fn translate_loop(&mut self, inner: &ir::Loop) {
+        let open = TokenKind::Bracket(BracketState::Open);
+        self.set_source(open, &inner.code_pos);
+        // the opening bracket runs once every time the loop is reached, the
+        // closing one once per iteration like in the interpreter
+        self.cost += 1;
+        self.charge();
+        self.translate_while(&inner.code_pos, &inner.end_pos, |translator| {
+            translator.translate_block(&inner.body);
+            // the check at the end of each iteration
+            let closed = TokenKind::Bracket(BracketState::Closed);
+            translator.set_source(closed, &inner.end_pos);
+            translator.cost += 1;
+            translator.charge();
+        });
+    }
 
-                builder.ins().brif(
-                    cell_value,
-                    inner_block,
-                    &[],
-                    after_block,
-                    &[],
-                );
-                // builder.ins().brz(cell_value, after_block, &[]);
-                // builder.ins().jump(inner_block, &[]);
+    // Takes the tokens translated since the last charge from the fuel,
+    // calls refuel once it runs out. Every straight run of tokens is charged
+    // before the next loop, I/O token or at the end of the iteration it is in
+    fn charge(&mut self) {
+        let fuel = match self.fuel {
+            Some(fuel) if self.cost > 0 => fuel,
+            _ => return,
+        };
+        let builder = &mut self.builder;
+        let fuel_value = builder.use_var(fuel);
+        let fuel_value = builder.ins().iadd_imm(fuel_value, -self.cost);
+        builder.def_var(fuel, fuel_value);
+        self.cost = 0;
+
+        let refuel_block = builder.create_block();
+        let after_block = builder.create_block();
+        builder.set_cold_block(refuel_block);
+        let is_empty =
+            builder.ins().icmp_imm(IntCC::SignedLessThan, fuel_value, 0);
+        builder
+            .ins()
+            .brif(is_empty, refuel_block, &[], after_block, &[]);
+        builder.seal_block(refuel_block);
+        builder.switch_to_block(refuel_block);
+
+        self.call_context(CONTEXT_REFUEL, self.refuel_sig, &[fuel_value]);
+        let fuel_value = self.load_context(CONTEXT_FUEL);
+        self.builder.def_var(fuel, fuel_value);
+        self.builder.ins().jump(after_block, &[]);
+        self.builder.seal_block(after_block);
+        self.builder.switch_to_block(after_block);
+    }
 
-                builder.switch_to_block(inner_block);
+    // while (*ptr) { body }, the positions are used for the checks at the
+    // start and end of each iteration
+    fn translate_while(
+        &mut self,
+        code_pos: &CodePos,
+        end_pos: &CodePos,
+        body: impl FnOnce(&mut Self),
+    ) {
+        let inner_block = self.builder.create_block();
+        let after_block = self.builder.create_block();
+
+        let cell_address = self.cell_address(0, code_pos);
+        let builder = &mut self.builder;
+        let cell_value =
+            builder
+                .ins()
+                .load(self.cell_type, self.mem_flags, cell_address, 0);
+
+        builder
+            .ins()
+            .brif(cell_value, inner_block, &[], after_block, &[]);
+        // builder.ins().brz(cell_value, after_block, &[]);
+        // builder.ins().jump(inner_block, &[]);
+
+        builder.switch_to_block(inner_block);
+
+        body(self);
+
+        let cell_address = self.cell_address(0, end_pos);
+        let builder = &mut self.builder;
+        let cell_value =
+            builder
+                .ins()
+                .load(self.cell_type, self.mem_flags, cell_address, 0);
+
+        builder
+            .ins()
+            .brif(cell_value, inner_block, &[], after_block, &[]);
+        // builder.ins().brnz(cell_value, inner_block, &[]);
+        // builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(inner_block);
+        builder.seal_block(after_block);
+
+        builder.switch_to_block(after_block);
+    }
 
-                stack.push((inner_block, after_block));
-            }
-            TokenKind::Bracket(BracketState::Closed) => {
-                let (inner_block, after_block) = match stack.pop() {
-                    Some(x) => x,
-                    None => Err("UnbalancedBrackets")?,
-                };
+    // Adds n to the pointer value, wrapping around the tape in TapeMode::Wrap
+    fn wrapped_offset(&mut self, pointer_value: Value, n: i64) -> Value {
+        let size = self.tape.size as i64;
+        // the pointer is always on the tape, so wrapping once is enough
+        let n = match self.tape.mode {
+            TapeMode::Wrap if n.abs() >= size => n.rem_euclid(size),
+            _ => n,
+        };
+        let builder = &mut self.builder;
+        let pointer_plus = builder.ins().iadd_imm(pointer_value, n);
+
+        if self.tape.mode != TapeMode::Wrap {
+            // checked when a cell is accessed
+            pointer_plus
+        } else if n > 0 {
+            let wrapped = builder.ins().iadd_imm(pointer_value, n - size);
+            let cmp = builder.ins().icmp_imm(
+                IntCC::SignedLessThan,
+                pointer_plus,
+                size,
+            );
+            builder.ins().select(cmp, pointer_plus, wrapped)
+        } else {
+            let wrapped = builder.ins().iadd_imm(pointer_value, n + size);
+            let cmp =
+                builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, pointer_plus, 0);
+            builder.ins().select(cmp, wrapped, pointer_plus)
+        }
+    }
 
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                let cell_value =
-                    builder.ins().load(I8, mem_flags, cell_address, 0);
+    // Branches to the exit block if a callback returned an error
+    fn check_callback_result(&mut self, result: Value) {
+        let builder = &mut self.builder;
+        let after_block = builder.create_block();
+
+        builder.ins().brif(
+            result,
+            self.exit_block,
+            &[result],
+            after_block,
+            &[],
+        );
+        // builder.ins().brnz(result, exit_block, &[result]);
+        // builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(after_block);
+        builder.switch_to_block(after_block);
+    }
+
+    // Moves the pointer by n cells, code_pos is reported if it leaves the
+    // tape in TapeMode::Strict
+    fn translate_move(&mut self, n: isize, code_pos: &CodePos) {
+        let pointer_value = self.builder.use_var(self.pointer);
+        let pointer_value = self.wrapped_offset(pointer_value, n as i64);
+        self.builder.def_var(self.pointer, pointer_value);
+        if self.tape.mode == TapeMode::Strict {
+            self.check_bounds(pointer_value, code_pos);
+        }
+    }
 
+    // ptr[n] += *ptr * factor
+    fn translate_mul_add(
+        &mut self,
+        n: isize,
+        factor: isize,
+        code_pos: &CodePos,
+    ) {
+        let mem_flags = self.mem_flags;
+        let from_address = self.cell_address(0, code_pos);
+        let from_value =
+            self.builder
+                .ins()
+                .load(self.cell_type, mem_flags, from_address, 0);
+
+        let after_block = match self.tape.mode {
+            TapeMode::Wrap => None,
+            _ => {
+                let builder = &mut self.builder;
+                let add_block = builder.create_block();
+                let after_block = builder.create_block();
                 builder.ins().brif(
-                    cell_value,
-                    inner_block,
+                    from_value,
+                    add_block,
                     &[],
                     after_block,
                     &[],
                 );
-                // builder.ins().brnz(cell_value, inner_block, &[]);
-                // builder.ins().jump(after_block, &[]);
+                builder.seal_block(add_block);
+                builder.switch_to_block(add_block);
+                Some(after_block)
+            }
+        };
 
-                builder.seal_block(inner_block);
-                builder.seal_block(after_block);
+        let to_address = self.cell_address(n, code_pos);
+        let builder = &mut self.builder;
+        let to_value =
+            builder.ins().load(self.cell_type, mem_flags, to_address, 0);
 
-                builder.switch_to_block(after_block);
-            }
-            TokenKind::Clear => {
-                let pointer_value = builder.use_var(pointer);
-                let cell_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                builder.ins().store(mem_flags, zero_byte, cell_address, 0);
-            }
-            TokenKind::Copy(n) => {
-                let n = n as i64;
-                let pointer_value = builder.use_var(pointer);
-                let to_add = builder.ins().iadd_imm(pointer_value, n);
-
-                let to_add = if n > 0 {
-                    let wrapped =
-                        builder.ins().iadd_imm(pointer_value, n - 30_000);
-                    let cmp = builder.ins().icmp_imm(
-                        IntCC::SignedLessThan,
-                        to_add,
-                        30_000,
-                    );
-                    builder.ins().select(cmp, to_add, wrapped)
-                } else {
-                    let wrapped =
-                        builder.ins().iadd_imm(pointer_value, n + 30_000);
-                    let cmp = builder.ins().icmp_imm(
-                        IntCC::SignedLessThan,
-                        to_add,
-                        0,
-                    );
-                    builder.ins().select(cmp, wrapped, to_add)
-                };
+        let from_value = match factor {
+            1 => from_value,
+            _ => builder.ins().imul_imm(from_value, factor as i64),
+        };
+        let sum = builder.ins().iadd(to_value, from_value);
+
+        builder.ins().store(mem_flags, sum, to_address, 0);
+
+        if let Some(after_block) = after_block {
+            builder.ins().jump(after_block, &[]);
+            builder.seal_block(after_block);
+            builder.switch_to_block(after_block);
+        }
+    }
+
+    // Address of the cell offset cells away from the pointer, code_pos is
+    // the position reported if the cell isn't on the tape
+    fn cell_address(&mut self, offset: isize, code_pos: &CodePos) -> Value {
+        let pointer_value = self.builder.use_var(self.pointer);
+        let index = match offset {
+            0 => pointer_value,
+            _ => self.wrapped_offset(pointer_value, offset as i64),
+        };
+
+        if self.tape.mode != TapeMode::Wrap {
+            self.check_bounds(index, code_pos);
+        }
 
-                let from_address =
-                    builder.ins().iadd(memory_address, pointer_value);
-                let to_address = builder.ins().iadd(memory_address, to_add);
+        let shift = self.cell_type.bytes().trailing_zeros() as i64;
+        let memory_address = self.builder.use_var(self.memory_address);
+        let byte_offset = match shift {
+            0 => index,
+            _ => self.builder.ins().ishl_imm(index, shift),
+        };
+        self.builder.ins().iadd(memory_address, byte_offset)
+    }
 
-                let from_value =
-                    builder.ins().load(I8, mem_flags, from_address, 0);
-                let to_value = builder.ins().load(I8, mem_flags, to_address, 0);
+    // Calls out_of_bounds, or grow in TapeMode::Grow, if index is past the
+    // end of the tape, negative indices are huge unsigned numbers
+    fn check_bounds(&mut self, index: Value, code_pos: &CodePos) {
+        let builder = &mut self.builder;
+        let tape_len = builder.use_var(self.tape_len);
+        let in_bounds =
+            builder.ins().icmp(IntCC::UnsignedLessThan, index, tape_len);
+
+        let off_tape_block = builder.create_block();
+        let after_block = builder.create_block();
+        builder.set_cold_block(off_tape_block);
+        builder
+            .ins()
+            .brif(in_bounds, after_block, &[], off_tape_block, &[]);
+
+        builder.seal_block(off_tape_block);
+        builder.switch_to_block(off_tape_block);
+
+        let pointer_type = self.pointer_type;
+        let args = [
+            code_pos.line as i64,
+            code_pos.col as i64,
+            code_pos.offset as i64,
+        ]
+        .map(|value| builder.ins().iconst(pointer_type, value));
+        self.call_context(
+            CONTEXT_OFF_TAPE,
+            self.off_tape_sig,
+            &[index, args[0], args[1], args[2]],
+        );
+
+        // the tape has grown, reload it
+        self.load_tape();
+        let builder = &mut self.builder;
+        builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(after_block);
+        builder.switch_to_block(after_block);
+    }
 
-                let sum = builder.ins().iadd(to_value, from_value);
+    fn translate_token(&mut self, token: &Token) {
+        self.set_source(token.kind.clone(), &token.code_pos);
+        self.cost += 1;
+        // the limits have to stop the program before it reads or writes what
+        // the interpreter wouldn't
+        if let TokenKind::Output { .. }
+        | TokenKind::Input { .. }
+        | TokenKind::Debug { .. } = token.kind
+        {
+            self.charge();
+        }
+        let mem_flags = self.mem_flags;
+        match token.kind {
+            TokenKind::ValMod { offset, delta } => {
+                let cell_address = self.cell_address(offset, &token.code_pos);
+                let builder = &mut self.builder;
+                let cell_value = builder.ins().load(
+                    self.cell_type,
+                    mem_flags,
+                    cell_address,
+                    0,
+                );
+                let cell_value =
+                    builder.ins().iadd_imm(cell_value, delta as i64);
+                builder.ins().store(mem_flags, cell_value, cell_address, 0);
+            }
+            TokenKind::PosMod(n) => self.translate_move(n, &token.code_pos),
+            TokenKind::Output { offset } => {
+                let cell_address = self.cell_address(offset, &token.code_pos);
+                let builder = &mut self.builder;
+                let cell_value = builder.ins().load(
+                    self.cell_type,
+                    mem_flags,
+                    cell_address,
+                    0,
+                );
+                // only the lowest byte is written
+                let cell_value = match self.cell_type {
+                    I8 => cell_value,
+                    _ => builder.ins().ireduce(I8, cell_value),
+                };
 
-                builder.ins().store(mem_flags, sum, to_address, 0);
+                self.call_context(CONTEXT_WRITE, self.write_sig, &[cell_value]);
+            }
+            TokenKind::Input { offset } => {
+                let cell_address = self.cell_address(offset, &token.code_pos);
+                self.call_context(
+                    CONTEXT_READ,
+                    self.read_sig,
+                    &[cell_address, self.eof_value, self.width_value],
+                );
+            }
+            TokenKind::Clear { offset } => {
+                let cell_address = self.cell_address(offset, &token.code_pos);
+                self.builder.ins().store(
+                    mem_flags,
+                    self.zero_cell,
+                    cell_address,
+                    0,
+                );
+            }
+            TokenKind::Scan(stride) => {
+                let code_pos = &token.code_pos;
+                // every stride is a step like in the interpreter
+                self.charge();
+                self.translate_while(code_pos, code_pos, |translator| {
+                    translator.translate_move(stride, code_pos);
+                    translator.cost += 1;
+                    translator.charge();
+                });
+            }
+            TokenKind::Copy(n) => self.translate_mul_add(n, 1, &token.code_pos),
+            TokenKind::MulAdd { offset, factor } => {
+                self.translate_mul_add(offset, factor, &token.code_pos)
+            }
+            TokenKind::Debug { radius } => {
+                let pointer_value = self.builder.use_var(self.pointer);
+                let radius_value =
+                    self.builder.ins().iconst(self.pointer_type, radius as i64);
+                self.call_context(
+                    CONTEXT_DEBUG,
+                    self.debug_sig,
+                    &[pointer_value, radius_value],
+                );
             }
-            TokenKind::Comment => {}
+            TokenKind::Bracket(_) | TokenKind::Comment => {}
         }
     }
+}
 
-    if !stack.is_empty() {
-        Err("UnbalancedBrackets")?
+unsafe extern "C" fn write(context: *mut RunContext, value: u8) -> *mut Error {
+    match (*context).output.write_all(&[value]) {
+        Err(err) => Box::into_raw(Box::new(Error::Io(err))),
+        _ => std::ptr::null_mut(),
     }
+}
 
-    builder.ins().return_(&[zero]);
+unsafe extern "C" fn read(
+    context: *mut RunContext,
+    buf: *mut u8,
+    eof: EofBehavior,
+    width: CellWidth,
+) -> *mut Error {
+    // cells may not be aligned
+    let store = |value: u32| match width {
+        CellWidth::U8 => *buf = value as u8,
+        CellWidth::U16 => (buf as *mut u16).write_unaligned(value as u16),
+        CellWidth::U32 => (buf as *mut u32).write_unaligned(value),
+    };
 
-    builder.switch_to_block(exit_block);
-    builder.seal_block(exit_block);
+    let context = &mut *context;
+    // make sure prompts are visible before blocking on input
+    if let Err(err) = context.output.flush() {
+        return Box::into_raw(Box::new(Error::Io(err)));
+    }
 
-    let result = builder.block_params(exit_block)[0];
-    builder.ins().return_(&[result]);
+    loop {
+        let mut value = 0;
+        let err = context.input.read_exact(std::slice::from_mut(&mut value));
 
-    builder.finalize();
+        if let Err(err) = err {
+            if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Box::into_raw(Box::new(Error::Io(err)));
+            }
+            if let Some(value) = eof.value() {
+                store(value);
+            }
+            return std::ptr::null_mut();
+        }
+
+        // ignore CR from Window's CRLF
+        if cfg!(target_os = "windows") && value == b'\r' {
+            continue;
+        }
+
+        store(value as u32);
 
-    let res = verify_function(&func, &*isa);
+        return std::ptr::null_mut();
+    }
+}
 
-    if let Err(errors) = res {
-        panic!("{}", errors);
+// Prints the cells up to radius away from pointer to stderr, pointer may be
+// off the tape outside of TapeMode::Wrap
+unsafe extern "C" fn debug(
+    context: *mut RunContext,
+    pointer: usize,
+    radius: usize,
+) -> *mut Error {
+    let context = &mut *context;
+    // keep the dump in order with the output before it
+    if let Err(err) = context.output.flush() {
+        return Box::into_raw(Box::new(Error::Io(err)));
     }
 
-    let mut ctx = Context::for_function(func);
-    let code = match ctx.compile(&*isa, &mut ControlPlane::default()) {
-        Ok(x) => x,
-        Err(err) => {
-            eprintln!("error compiling: {:?}", err);
-            std::process::exit(4);
+    let (cells, cell_bytes) = (&context.cells, context.cell_bytes);
+    let cell = |index: usize| {
+        let bytes = &cells[index * cell_bytes..(index + 1) * cell_bytes];
+        match *bytes {
+            [byte] => byte as u32,
+            [a, b] => u16::from_ne_bytes([a, b]) as u32,
+            [a, b, c, d] => u32::from_ne_bytes([a, b, c, d]),
+            _ => unreachable!(),
         }
     };
+    let line = debug_dump(pointer as isize, radius, context.len, cell);
+    match std::io::stderr().write_all(line.as_bytes()) {
+        Err(err) => Box::into_raw(Box::new(Error::Io(err))),
+        _ => std::ptr::null_mut(),
+    }
+}
 
-    let code = code.code_buffer().to_vec();
+// Hands out more fuel, fuel is what the generated code has left and
+// negative once it ran more tokens than it was given
+unsafe extern "C" fn refuel(
+    context: *mut RunContext,
+    fuel: isize,
+) -> *mut Error {
+    let context = &mut *context;
+    let steps = context.fueled.wrapping_sub(fuel as u64);
+    let limit = match context.limits.steps {
+        Some(limit) if steps > limit => Some(Limit::Steps(limit)),
+        _ => match context.limits.time {
+            Some(time) if context.start.elapsed() > time => {
+                Some(Limit::Time(time))
+            }
+            _ => None,
+        },
+    };
+    if let Some(limit) = limit {
+        return Box::into_raw(Box::new(Error::LimitExceeded(limit)));
+    }
 
-    Ok(code)
+    let left = context.limits.steps.map_or(u64::MAX, |limit| limit - steps);
+    let fuel = left.min(FUEL_CHUNK);
+    context.fuel = fuel as isize;
+    context.fueled = steps + fuel;
+    std::ptr::null_mut()
 }
 
-extern "C" fn write(value: u8) -> *mut std::io::Error {
-    // Writing a non-UTF-8 byte sequence on Windows error out.
-    if cfg!(target_os = "windows") && value >= 128 {
-        return std::ptr::null_mut();
-    }
+extern "C" fn out_of_bounds(
+    _context: *mut RunContext,
+    _index: usize,
+    line: usize,
+    col: usize,
+    offset: usize,
+) -> *mut Error {
+    let code_pos = CodePos { line, col, offset };
+    Box::into_raw(Box::new(Error::OutOfBounds(code_pos)))
+}
 
-    let mut stdout = std::io::stdout().lock();
+// optimize doesn't sink moves in TapeMode::Strict, so every index off the
+// tape there is one the pointer moved to
+extern "C" fn pointer_off_tape(
+    _context: *mut RunContext,
+    _index: usize,
+    line: usize,
+    col: usize,
+    offset: usize,
+) -> *mut Error {
+    let code_pos = CodePos { line, col, offset };
+    Box::into_raw(Box::new(Error::PointerOffTape(code_pos)))
+}
 
-    let result = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+// Grows the tape so index is on it, indices left of the tape are huge when
+// seen as unsigned and still an error
+unsafe extern "C" fn grow(
+    context: *mut RunContext,
+    index: usize,
+    line: usize,
+    col: usize,
+    offset: usize,
+) -> *mut Error {
+    if (index as isize) < 0 {
+        return out_of_bounds(context, index, line, col, offset);
+    }
 
-    match result {
-        Err(err) => Box::into_raw(Box::new(err)),
-        _ => std::ptr::null_mut(),
+    let context = &mut *context;
+    let size = (context.len * 2).max(index + 1);
+    // this runs in JIT code, so failing to allocate has to be an error
+    // rather than a panic
+    let bytes = match size.checked_mul(context.cell_bytes) {
+        Some(bytes) if bytes <= isize::MAX as usize => bytes,
+        _ => return Box::into_raw(Box::new(Error::OutOfMemory)),
+    };
+    let additional = bytes - context.cells.len();
+    if context.cells.try_reserve_exact(additional).is_err() {
+        return Box::into_raw(Box::new(Error::OutOfMemory));
     }
+    context.cells.resize(bytes, 0);
+    context.ptr = context.cells.as_mut_ptr();
+    context.len = size;
+    std::ptr::null_mut()
 }
 
-unsafe extern "C" fn read(buf: *mut u8) -> *mut std::io::Error {
-    let mut stdin = std::io::stdin().lock();
-    loop {
-        let mut value = 0;
-        let err = stdin.read_exact(std::slice::from_mut(&mut value));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse, Dialect};
+
+    const WIDTHS: [CellWidth; 3] =
+        [CellWidth::U8, CellWidth::U16, CellWidth::U32];
+
+    fn run_jit(
+        source: &str,
+        eof: EofBehavior,
+        cell_width: CellWidth,
+        input: &[u8],
+    ) -> Vec<u8> {
+        let program = parse(source, Dialect::default()).unwrap();
+        let program = optimize(program, TapeMode::Wrap);
+        let jit = Jit {
+            eof,
+            tape: TapeConfig {
+                cell_width,
+                ..TapeConfig::default()
+            },
+            limits: Limits::default(),
+        };
+        let mut output = vec![];
+        jit.run(&program, &mut &input[..], &mut output).unwrap();
+        output
+    }
 
-        if let Err(err) = err {
-            if err.kind() != std::io::ErrorKind::UnexpectedEof {
-                return Box::into_raw(Box::new(err));
-            }
-            value = 0;
+    #[test]
+    fn jit_reads_input() {
+        for cell_width in WIDTHS {
+            let output =
+                run_jit(",+.,+.", EofBehavior::Zero, cell_width, b"AB");
+            assert_eq!(output, b"BC");
         }
+    }
 
-        // ignore CR from Window's CRLF
-        if cfg!(target_os = "windows") && value == b'\r' {
-            continue;
+    #[test]
+    fn jit_eof_behavior() {
+        // the cell is 5 before reading past the end of input, the +
+        // shows MinusOne sets it to the largest value of every cell width
+        let cases = [
+            (EofBehavior::Unchanged, 6),
+            (EofBehavior::Zero, 1),
+            (EofBehavior::MinusOne, 0),
+        ];
+        for cell_width in WIDTHS {
+            for (eof, expected) in cases {
+                let output = run_jit("+++++,+.", eof, cell_width, b"");
+                assert_eq!(output, [expected], "{:?} {:?}", eof, cell_width);
+            }
         }
+    }
 
-        *buf = value;
+    #[test]
+    fn jit_cell_width() {
+        // builds 256 and 65536 with multiply-add loops, then prints whether
+        // each of them is still nonzero at the cell width
+        let source = format!(
+            "{}[>{}<-]>[>{}>+<<-]>>[[-]>+<]>.<<[[-]>+<]>.",
+            "+".repeat(16),
+            "+".repeat(16),
+            "+".repeat(256)
+        );
+        let cases = [
+            (CellWidth::U8, [0, 0]),
+            (CellWidth::U16, [1, 0]),
+            (CellWidth::U32, [1, 1]),
+        ];
+        for (cell_width, expected) in cases {
+            let output = run_jit(&source, EofBehavior::Zero, cell_width, b"");
+            assert_eq!(output, expected, "{:?}", cell_width);
+        }
+    }
 
-        return std::ptr::null_mut();
+    #[test]
+    fn grow_reports_out_of_memory() {
+        for cell_width in WIDTHS {
+            let tape = TapeConfig::new(4, TapeMode::Grow, cell_width).unwrap();
+            let (mut input, mut output) = (&b""[..], vec![]);
+            let mut context = RunContext::new(
+                tape,
+                Limits::default(),
+                &mut input,
+                &mut output,
+            );
+            // far more than can be allocated, or even addressed for wider cells
+            let index = isize::MAX as usize - 1;
+            let error =
+                unsafe { Box::from_raw(grow(&mut context, index, 1, 1, 0)) };
+            assert!(matches!(*error, Error::OutOfMemory), "{:?}", error);
+            assert_eq!(context.len, 4);
+        }
     }
 }
@@ -0,0 +1,465 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::{
+    error::source_snippet, interpreter::Machine, ir::Block, BracketState,
+    EofBehavior, Error, TapeConfig, Token, TokenKind,
+};
+
+/*
+A command line debugger over the interpreter. Commands are read a line at a
+time from the same input the program reads from, so input for `,` is typed
+when the program asks for it. The debugger writes to console, the program to
+output. Errors of the program stop it at the token that failed, the tape can
+still be inspected afterwards.
+*/
+
+const HELP: &str = "\
+s, step [N]           run the next N tokens, 1 by default
+n, next               like step, but runs a whole loop at once
+c, continue           run until a breakpoint, a watchpoint or the end
+b, break LINE[:COL]   stop before the token at a position
+w, watch CELL         stop after the value of a cell changes
+i, info               list breakpoints and watchpoints
+d, delete             remove all breakpoints and watchpoints
+t, tape [RADIUS]      show the cells around the pointer, 8 by default
+l, list               show the source around the next token
+q, quit               stop debugging
+an empty line repeats the last command
+";
+
+// Runs a program under the debugger
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Debugger {
+    pub eof: EofBehavior,
+    pub tape: TapeConfig,
+}
+
+impl Debugger {
+    // Debugs program until it ends or the user quits, source is the text it
+    // was parsed from. Pass the program straight from parse, optimize merges
+    // and moves tokens so stepping through them wouldn't follow the source
+    pub fn run(
+        &self,
+        program: &Block,
+        source: &str,
+        input: &mut impl BufRead,
+        output: &mut dyn Write,
+        console: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let mut session = Session {
+            machine: Machine::new(program, self.eof, self.tape)?,
+            source,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            failed: false,
+            read_input: false,
+        };
+        session.show_position(console)?;
+
+        let mut last_command = String::new();
+        loop {
+            output.flush()?;
+            write!(console, "(rsbf) ")?;
+            console.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(console)?;
+                return Ok(());
+            }
+            let read_input = std::mem::take(&mut session.read_input);
+            let line = match line.trim() {
+                // the rest of a line typed as input for the program
+                "" if read_input => continue,
+                "" => last_command.clone(),
+                line => line.to_string(),
+            };
+            last_command = line.clone();
+
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let argument = words.next();
+            match command {
+                "" => {}
+                "s" | "step" => {
+                    let mut steps = match parse_number(argument, 1) {
+                        Some(steps) if steps > 0 => steps,
+                        _ => {
+                            writeln!(console, "usage: step [N]")?;
+                            continue;
+                        }
+                    };
+                    session.run(input, output, console, |_| {
+                        steps -= 1;
+                        steps == 0
+                    })?;
+                }
+                "n" | "next" => {
+                    let machine = &session.machine;
+                    // the token after the matching bracket ends the loop
+                    let end = match machine.tokens.get(machine.pos) {
+                        Some(Token {
+                            kind: TokenKind::Bracket(BracketState::Open),
+                            ..
+                        }) => machine.jumps[machine.pos] + 1,
+                        _ => machine.pos + 1,
+                    };
+                    session.run(input, output, console, |machine| {
+                        machine.pos == end
+                    })?;
+                }
+                "c" | "continue" => {
+                    session.run(input, output, console, |_| false)?
+                }
+                "b" | "break" => match argument.and_then(Breakpoint::parse) {
+                    Some(breakpoint) => {
+                        writeln!(
+                            console,
+                            "breakpoint {} at {}",
+                            session.breakpoints.len() + 1,
+                            breakpoint
+                        )?;
+                        session.breakpoints.push(breakpoint);
+                    }
+                    None => writeln!(console, "usage: break LINE[:COL]")?,
+                },
+                "w" | "watch" => match argument.and_then(|s| s.parse().ok()) {
+                    Some(index) => {
+                        let value = session.machine.cell(index);
+                        writeln!(console, "watching cell {}", index)?;
+                        session.watchpoints.push((index, value));
+                    }
+                    None => writeln!(console, "usage: watch CELL")?,
+                },
+                "i" | "info" => session.show_info(console)?,
+                "d" | "delete" => {
+                    session.breakpoints.clear();
+                    session.watchpoints.clear();
+                    writeln!(
+                        console,
+                        "deleted all breakpoints and watchpoints"
+                    )?;
+                }
+                "t" | "tape" => match parse_number(argument, 8) {
+                    Some(radius) => session.show_tape(radius, console)?,
+                    None => writeln!(console, "usage: tape [RADIUS]")?,
+                },
+                "l" | "list" => session.show_source(console)?,
+                "q" | "quit" => return Ok(()),
+                "h" | "help" => write!(console, "{}", HELP)?,
+                _ => {
+                    writeln!(console, "unknown command {}, try help", command)?
+                }
+            }
+        }
+    }
+}
+
+// Parses an optional number, default if it's missing
+fn parse_number(argument: Option<&str>, default: usize) -> Option<usize> {
+    match argument {
+        None => Some(default),
+        Some(argument) => argument.parse().ok(),
+    }
+}
+
+// Stops before tokens that span the position
+struct Breakpoint {
+    line: usize,
+    // None stops at every token on the line
+    col: Option<usize>,
+}
+
+impl Breakpoint {
+    fn parse(s: &str) -> Option<Self> {
+        let (line, col) = match s.split_once(':') {
+            Some((line, col)) => (line, Some(col.parse().ok()?)),
+            None => (s, None),
+        };
+        Some(Breakpoint {
+            line: line.parse().ok()?,
+            col,
+        })
+    }
+
+    fn hits(&self, token: &Token) -> bool {
+        let start = (token.code_pos.line, token.code_pos.col);
+        let end = (token.end_pos.line, token.end_pos.col);
+        match self.col {
+            None => start.0 <= self.line && self.line <= end.0,
+            Some(col) => start <= (self.line, col) && (self.line, col) <= end,
+        }
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.col {
+            Some(col) => write!(f, "{}:{}", self.line, col),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+struct Session<'a> {
+    machine: Machine,
+    source: &'a str,
+    breakpoints: Vec<Breakpoint>,
+    // watched cells and their last value, None if it isn't on the tape yet
+    watchpoints: Vec<(usize, Option<isize>)>,
+    // the next token failed, running it again would fail again
+    failed: bool,
+    // the program read input since the last command
+    read_input: bool,
+}
+
+// Input of the program, remembers if it was read from
+struct ProgramInput<'a, R> {
+    input: &'a mut R,
+    used: bool,
+}
+
+impl<R: Read> Read for ProgramInput<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.used = true;
+        self.input.read(buf)
+    }
+}
+
+impl Session<'_> {
+    // Runs at least one token, then until done returns true, a breakpoint or
+    // watchpoint is hit, the program fails or it ends
+    fn run(
+        &mut self,
+        input: &mut impl BufRead,
+        output: &mut dyn Write,
+        console: &mut dyn Write,
+        mut done: impl FnMut(&Machine) -> bool,
+    ) -> Result<(), Error> {
+        if self.machine.is_done() || self.failed {
+            writeln!(console, "the program isn't running")?;
+            return Ok(());
+        }
+        let mut input = ProgramInput { input, used: false };
+        loop {
+            let result = self.machine.step(&mut input, output);
+            self.read_input |= input.used;
+            if let Err(error) = result {
+                output.flush()?;
+                writeln!(console, "error: {}", error)?;
+                self.failed = true;
+                break;
+            }
+            if self.machine.is_done() || self.check_watchpoints(console)? {
+                break;
+            }
+            if done(&self.machine) {
+                break;
+            }
+            let token = &self.machine.tokens[self.machine.pos];
+            let hit = self.breakpoints.iter().position(|b| b.hits(token));
+            if let Some(index) = hit {
+                writeln!(console, "breakpoint {}", index + 1)?;
+                break;
+            }
+        }
+        output.flush()?;
+        self.show_position(console)
+    }
+
+    // Reports the first watched cell that changed, true if one did
+    fn check_watchpoints(
+        &mut self,
+        console: &mut dyn Write,
+    ) -> Result<bool, Error> {
+        for (index, last_value) in &mut self.watchpoints {
+            let value = self.machine.cell(*index);
+            if value != *last_value {
+                let show = |value: Option<isize>| match value {
+                    Some(value) => value.to_string(),
+                    None => "nothing".to_string(),
+                };
+                writeln!(
+                    console,
+                    "cell {} changed from {} to {}",
+                    index,
+                    show(*last_value),
+                    show(value)
+                )?;
+                *last_value = value;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Prints the next token and where it is in the source
+    fn show_position(&self, console: &mut dyn Write) -> Result<(), Error> {
+        let machine = &self.machine;
+        match machine.tokens.get(machine.pos) {
+            None => writeln!(console, "the program ended")?,
+            Some(token) => {
+                // left of the tape the pointer wrapped around below 0
+                let pointer = match machine.mempos < machine.tape_len() {
+                    true => format!("pointer at cell {}", machine.mempos),
+                    false => format!(
+                        "pointer off the tape at {}",
+                        machine.mempos as isize
+                    ),
+                };
+                writeln!(
+                    console,
+                    "next: {:?} at {}, {}",
+                    token.kind, token.code_pos, pointer
+                )?;
+                write!(
+                    console,
+                    "{}",
+                    source_snippet(self.source, &token.code_pos)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Prints the lines around the next token, marking its line
+    fn show_source(&self, console: &mut dyn Write) -> Result<(), Error> {
+        let token = match self.machine.tokens.get(self.machine.pos) {
+            Some(token) => token,
+            None => {
+                writeln!(console, "the program ended")?;
+                return Ok(());
+            }
+        };
+        let current = token.code_pos.line;
+        let first = current.saturating_sub(3).max(1);
+        for (index, line) in
+            self.source.lines().enumerate().skip(first - 1).take(7)
+        {
+            let marker = if index + 1 == current { '>' } else { ' ' };
+            writeln!(console, "{}{:5} | {}", marker, index + 1, line)?;
+        }
+        Ok(())
+    }
+
+    // Prints the cells up to radius away from the pointer
+    fn show_tape(
+        &self,
+        radius: usize,
+        console: &mut dyn Write,
+    ) -> Result<(), Error> {
+        // the pointer may be off the tape on either side, like in debug_dump
+        let mempos = self.machine.mempos as isize;
+        let radius = isize::try_from(radius).unwrap_or(isize::MAX);
+        let first = mempos.saturating_sub(radius).max(0);
+        let last = mempos
+            .saturating_add(radius)
+            .min(self.machine.tape_len() as isize - 1);
+        if first > last {
+            writeln!(console, "pointer off the tape at {}", mempos)?;
+            return Ok(());
+        }
+
+        let (mut indices, mut values, mut pointer) =
+            (String::new(), String::new(), String::new());
+        for index in first..=last {
+            let value = self.machine.cell(index as usize).unwrap_or(0);
+            let width = index.to_string().len().max(value.to_string().len());
+            indices += &format!(" {:>width$}", index, width = width);
+            values += &format!(" {:>width$}", value, width = width);
+            let marker = if index == mempos { "^" } else { "" };
+            pointer += &format!(" {:>width$}", marker, width = width);
+        }
+        writeln!(console, "cell {}", indices)?;
+        writeln!(console, "value{}", values)?;
+        writeln!(console, "     {}", pointer)?;
+        Ok(())
+    }
+
+    // Prints the breakpoints and watchpoints
+    fn show_info(&self, console: &mut dyn Write) -> Result<(), Error> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            writeln!(console, "no breakpoints or watchpoints")?;
+        }
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            writeln!(console, "breakpoint {} at {}", index + 1, breakpoint)?;
+        }
+        for (index, _) in &self.watchpoints {
+            let value = self.machine.cell(*index).unwrap_or(0);
+            writeln!(console, "watching cell {}, now {}", index, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Dialect};
+
+    // Console and program output of a debugging session
+    fn debug(source: &str, script: &str) -> (String, String) {
+        let program = parse(source, Dialect::default()).unwrap();
+        let (mut output, mut console) = (vec![], vec![]);
+        Debugger::default()
+            .run(
+                &program,
+                source,
+                &mut script.as_bytes(),
+                &mut output,
+                &mut console,
+            )
+            .unwrap();
+        (
+            String::from_utf8(console).unwrap(),
+            String::from_utf8(output).unwrap(),
+        )
+    }
+
+    // A `,` in the program reads the next line of the script, the rest of
+    // that line is skipped, the empty line after it repeats the step
+    #[test]
+    fn scripted_session() {
+        let source = ",+.\n>++[>+++<-]>[-.]\n";
+        let script = "s\nA\n\ns 2\nw 2\nc\nt 2\nd\nb 2:12\nc\ns\nn\nc\nq\n";
+        let (console, output) = debug(source, script);
+        // what the debugger printed before each prompt
+        let replies: Vec<&str> = console.split("(rsbf) ").collect();
+        let expected = [
+            "next: Input { offset: 0 } at 1:1, pointer at cell 0\n",
+            "next: ValMod { offset: 0, delta: 1 } at 1:2, pointer at cell 0\n",
+            "",
+            "next: Output { offset: 0 } at 1:3, pointer at cell 0\n",
+            "next: ValMod { offset: 0, delta: 1 } at 2:2, pointer at cell 1\n",
+            "watching cell 2\n",
+            // every move and add of the source is a step of its own
+            "cell 2 changed from 0 to 1\n\
+             next: ValMod { offset: 0, delta: 1 } at 2:7, pointer at cell 2\n",
+            "cell   0 1 2 3 4\nvalue 66 2 1 0 0\n           ^    \n",
+            "deleted all breakpoints and watchpoints\n",
+            "breakpoint 1 at 2:12\n",
+            "breakpoint 1\nnext: PosMod(1) at 2:12, pointer at cell 1\n",
+            "next: Bracket(Open) at 2:13, pointer at cell 2\n",
+            "the program ended\n",
+            "the program isn't running\n",
+            "",
+        ];
+        assert_eq!(replies.len(), expected.len(), "{}", console);
+        for (reply, expected) in replies.iter().zip(expected) {
+            assert!(reply.starts_with(expected), "{:?} {:?}", reply, expected);
+        }
+        // the source snippet follows the position
+        assert!(
+            replies[10].ends_with("2 | >++[>+++<-]>[-.]\n  |            ^\n")
+        );
+        assert_eq!(output, "B\x05\x04\x03\x02\x01\0");
+    }
+
+    #[test]
+    fn end_of_script_stops_debugging() {
+        let (console, output) = debug("+[.-]", "");
+        assert_eq!(console.matches("(rsbf) ").count(), 1);
+        assert!(console.ends_with("(rsbf) \n"));
+        assert_eq!(output, "");
+    }
+}
@@ -0,0 +1,444 @@
+use cranelift::codegen::{
+    control::ControlPlane, ir::Function, isa::TargetIsa, Context,
+};
+use target_lexicon::{Architecture, OperatingSystem, Triple};
+
+use crate::{
+    aot::{
+        self, BUFFER_SIZE, CONTEXT_BUFFER, CONTEXT_SCRATCH, CONTEXT_SLOTS,
+        CONTEXT_SYS_GROW, CONTEXT_SYS_READ, CONTEXT_SYS_WRITE, SCRATCH_SIZE,
+    },
+    codegen::{
+        self, CONTEXT_DEBUG, CONTEXT_LEN, CONTEXT_OFF_TAPE, CONTEXT_PTR,
+        CONTEXT_READ, CONTEXT_WRITE,
+    },
+    ir, EofBehavior, Error, TapeConfig,
+};
+
+/*
+Writes static executables for linux without a linker. The file has one
+segment with the code and one with the context, the output buffer and the
+tape, the last three are bss so the file stays small. Everything is at a
+fixed address so the context is filled in here, the only code that isn't
+generated by cranelift are the entry point and the syscalls below.
+*/
+
+// Address the file is loaded at
+const BASE: u64 = 0x400000;
+// Largest page size of the supported architectures
+const PAGE_SIZE: u64 = 0x10000;
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const PROGRAM_HEADERS: u64 = 3;
+
+// Hand written code for an architecture, see the comments for the assembly
+struct Stubs {
+    machine: u16,
+    // start with the addresses of the context, the program and aot::start
+    // at the given offsets, exits with the return value of aot::start
+    entry: &'static [u8],
+    entry_addresses: [usize; 3],
+    // write(fd, buf, len) and read(fd, buf, len) syscalls
+    write: &'static [u8],
+    read: &'static [u8],
+    // grow(address, old_bytes, new_bytes), maps new zeroed memory and copies
+    // the old bytes over, the old memory is never unmapped since the first
+    // tape is in bss
+    grow: &'static [u8],
+}
+
+#[rustfmt::skip]
+const X86_64: Stubs = Stubs {
+    machine: 62,
+    entry: &[
+        0x48, 0xbf, 0, 0, 0, 0, 0, 0, 0, 0, // movabs rdi, context
+        0x48, 0xbe, 0, 0, 0, 0, 0, 0, 0, 0, // movabs rsi, program
+        0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, // movabs rax, start
+        0xff, 0xd0,                         // call rax
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0xe7, 0x00, 0x00, 0x00,       // mov eax, 231 (exit_group)
+        0x0f, 0x05,                         // syscall
+    ],
+    entry_addresses: [2, 12, 22],
+    write: &[
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, 1 (write)
+        0x0f, 0x05,                         // syscall
+        0xc3,                               // ret
+    ],
+    read: &[
+        0x31, 0xc0,                         // xor eax, eax (read)
+        0x0f, 0x05,                         // syscall
+        0xc3,                               // ret
+    ],
+    grow: &[
+        0x57,                               // push rdi
+        0x56,                               // push rsi
+        0x48, 0x89, 0xd6,                   // mov rsi, rdx
+        0x31, 0xff,                         // xor edi, edi
+        0xba, 0x03, 0x00, 0x00, 0x00,       // mov edx, PROT_READ | PROT_WRITE
+        0x41, 0xba, 0x22, 0x00, 0x00, 0x00, // mov r10d, MAP_PRIVATE | MAP_ANONYMOUS
+        0x49, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov r8, -1
+        0x45, 0x31, 0xc9,                   // xor r9d, r9d
+        0xb8, 0x09, 0x00, 0x00, 0x00,       // mov eax, 9 (mmap)
+        0x0f, 0x05,                         // syscall
+        0x59,                               // pop rcx
+        0x5e,                               // pop rsi
+        0x48, 0x3d, 0x00, 0xf0, 0xff, 0xff, // cmp rax, -4096
+        0x77, 0x06,                         // ja fail
+        0x48, 0x89, 0xc7,                   // mov rdi, rax
+        0xf3, 0xa4,                         // rep movsb
+        0xc3,                               // ret
+        0x31, 0xc0,                         // fail: xor eax, eax
+        0xc3,                               // ret
+    ],
+};
+
+#[rustfmt::skip]
+const AARCH64: Stubs = Stubs {
+    machine: 183,
+    entry: &[
+        0xc0, 0x00, 0x00, 0x58, // ldr x0, context
+        0xe1, 0x00, 0x00, 0x58, // ldr x1, program
+        0x02, 0x01, 0x00, 0x58, // ldr x2, start
+        0x40, 0x00, 0x3f, 0xd6, // blr x2
+        0xc8, 0x0b, 0x80, 0xd2, // mov x8, 94 (exit_group)
+        0x01, 0x00, 0x00, 0xd4, // svc 0
+        0, 0, 0, 0, 0, 0, 0, 0, // context: .quad
+        0, 0, 0, 0, 0, 0, 0, 0, // program: .quad
+        0, 0, 0, 0, 0, 0, 0, 0, // start: .quad
+    ],
+    entry_addresses: [24, 32, 40],
+    write: &[
+        0x08, 0x08, 0x80, 0xd2, // mov x8, 64 (write)
+        0x01, 0x00, 0x00, 0xd4, // svc 0
+        0xc0, 0x03, 0x5f, 0xd6, // ret
+    ],
+    read: &[
+        0xe8, 0x07, 0x80, 0xd2, // mov x8, 63 (read)
+        0x01, 0x00, 0x00, 0xd4, // svc 0
+        0xc0, 0x03, 0x5f, 0xd6, // ret
+    ],
+    grow: &[
+        0xe9, 0x03, 0x00, 0xaa, // mov x9, x0
+        0xea, 0x03, 0x01, 0xaa, // mov x10, x1
+        0xe1, 0x03, 0x02, 0xaa, // mov x1, x2
+        0x00, 0x00, 0x80, 0xd2, // mov x0, 0
+        0x62, 0x00, 0x80, 0xd2, // mov x2, PROT_READ | PROT_WRITE
+        0x43, 0x04, 0x80, 0xd2, // mov x3, MAP_PRIVATE | MAP_ANONYMOUS
+        0x04, 0x00, 0x80, 0x92, // mov x4, -1
+        0x05, 0x00, 0x80, 0xd2, // mov x5, 0
+        0xc8, 0x1b, 0x80, 0xd2, // mov x8, 222 (mmap)
+        0x01, 0x00, 0x00, 0xd4, // svc 0
+        0x1f, 0xfc, 0x3f, 0xb1, // cmn x0, 4095
+        0x02, 0x01, 0x00, 0x54, // b.hs fail
+        0xeb, 0x03, 0x00, 0xaa, // mov x11, x0
+        0xaa, 0x00, 0x00, 0xb4, // loop: cbz x10, done
+        0x2c, 0x15, 0x40, 0x38, // ldrb w12, [x9], 1
+        0x6c, 0x15, 0x00, 0x38, // strb w12, [x11], 1
+        0x4a, 0x05, 0x00, 0xd1, // sub x10, x10, 1
+        0xfc, 0xff, 0xff, 0x17, // b loop
+        0xc0, 0x03, 0x5f, 0xd6, // done: ret
+        0x00, 0x00, 0x80, 0xd2, // fail: mov x0, 0
+        0xc0, 0x03, 0x5f, 0xd6, // ret
+    ],
+};
+
+// Compiles the program into a static linux executable for triple
+pub fn compile_elf(
+    program: &ir::Block,
+    eof: EofBehavior,
+    tape: TapeConfig,
+    triple: Triple,
+) -> Result<Vec<u8>, Error> {
+    let stubs = match (triple.architecture, triple.operating_system) {
+        (Architecture::X86_64, OperatingSystem::Linux) => &X86_64,
+        (Architecture::Aarch64(_), OperatingSystem::Linux) => &AARCH64,
+        _ => return Err(Error::UnsupportedElfTarget(triple.to_string())),
+    };
+    let isa = codegen::target_isa(triple, false)?;
+
+    let mut text = vec![];
+    // adds code to the text segment and returns its offset in it
+    let mut push = |code: &[u8]| {
+        let offset = text.len();
+        text.extend_from_slice(code);
+        text.resize((text.len() as u64).next_multiple_of(16) as usize, 0);
+        offset as u64
+    };
+    let entry = push(stubs.entry);
+    let sys_write = push(stubs.write);
+    let sys_read = push(stubs.read);
+    let sys_grow = push(stubs.grow);
+    let start = push(&compile_function(aot::start(&*isa)?, &*isa)?);
+    let mut runtime = vec![];
+    for (_, func) in aot::runtime(program, eof, tape, &*isa)? {
+        runtime.push(push(&compile_function(func, &*isa)?));
+    }
+
+    let text_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE * PROGRAM_HEADERS)
+        .next_multiple_of(16);
+    let text_address = BASE + text_offset;
+    let data_offset = (text_offset + text.len() as u64).next_multiple_of(16);
+    // keep the offset in the page the same, so the file doesn't need padding
+    let data_address = (BASE + data_offset).next_multiple_of(PAGE_SIZE)
+        + data_offset % PAGE_SIZE;
+
+    // data segment layout, only the context is stored in the file
+    let context_size = CONTEXT_SLOTS as u64 * 8;
+    let buffer_address = data_address + context_size.next_multiple_of(16);
+    let scratch_address = buffer_address + BUFFER_SIZE as u64;
+    let tape_address =
+        (scratch_address + SCRATCH_SIZE as u64).next_multiple_of(16);
+    let data_end = tape_address + tape.bytes() as u64;
+
+    let mut context = vec![0; CONTEXT_SLOTS];
+    let mut set = |index: i32, value: u64| context[index as usize] = value;
+    set(CONTEXT_PTR, tape_address);
+    set(CONTEXT_LEN, tape.size as u64);
+    set(CONTEXT_WRITE, text_address + runtime[1]);
+    set(CONTEXT_READ, text_address + runtime[2]);
+    set(CONTEXT_OFF_TAPE, text_address + runtime[3]);
+    set(CONTEXT_DEBUG, text_address + runtime[4]);
+    set(CONTEXT_SYS_WRITE, text_address + sys_write);
+    set(CONTEXT_SYS_READ, text_address + sys_read);
+    set(CONTEXT_SYS_GROW, text_address + sys_grow);
+    set(CONTEXT_BUFFER, buffer_address);
+    set(CONTEXT_SCRATCH, scratch_address);
+
+    let addresses = [
+        data_address,
+        text_address + runtime[0],
+        text_address + start,
+    ];
+    for (offset, address) in stubs.entry_addresses.iter().zip(addresses) {
+        let offset = (entry as usize) + offset;
+        text[offset..offset + 8].copy_from_slice(&address.to_le_bytes());
+    }
+
+    let mut file = vec![];
+    // ELF header
+    file.extend_from_slice(b"\x7fELF");
+    // 64 bit, little endian, version 1, System V ABI
+    file.extend_from_slice(&[2, 1, 1, 0]);
+    file.resize(16, 0);
+    put16(&mut file, 2); // executable
+    put16(&mut file, stubs.machine);
+    put32(&mut file, 1); // version
+    put64(&mut file, text_address + entry);
+    put64(&mut file, HEADER_SIZE); // program headers
+    put64(&mut file, 0); // section headers
+    put32(&mut file, 0); // flags
+    put16(&mut file, HEADER_SIZE as u16);
+    put16(&mut file, PROGRAM_HEADER_SIZE as u16);
+    put16(&mut file, PROGRAM_HEADERS as u16);
+    put16(&mut file, 64); // section header size
+    put16(&mut file, 0); // section headers
+    put16(&mut file, 0); // section name table
+
+    // code, readable and executable
+    program_header(
+        &mut file,
+        1,
+        0b101,
+        text_offset,
+        text_address,
+        text.len() as u64,
+        text.len() as u64,
+    );
+    // context, buffer, scratch space and tape, readable and writable
+    program_header(
+        &mut file,
+        1,
+        0b110,
+        data_offset,
+        data_address,
+        context_size,
+        data_end - data_address,
+    );
+    // PT_GNU_STACK, makes the stack non executable
+    program_header(&mut file, 0x6474e551, 0b110, 0, 0, 0, 0);
+
+    file.resize(text_offset as usize, 0);
+    file.extend_from_slice(&text);
+    file.resize(data_offset as usize, 0);
+    for value in context {
+        put64(&mut file, value);
+    }
+    Ok(file)
+}
+
+// Compiles a function, fails if it has relocations since nothing would
+// apply them
+fn compile_function(
+    func: Function,
+    isa: &dyn TargetIsa,
+) -> Result<Vec<u8>, Error> {
+    let mut ctx = Context::for_function(func);
+    let code = ctx
+        .compile(isa, &mut ControlPlane::default())
+        .map_err(|err| err.inner)?;
+    if !code.buffer.relocs().is_empty() {
+        return Err(Error::ElfRelocations(isa.triple().to_string()));
+    }
+    Ok(code.code_buffer().to_vec())
+}
+
+fn program_header(
+    file: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+) {
+    put32(file, kind);
+    put32(file, flags);
+    put64(file, offset);
+    put64(file, address); // virtual
+    put64(file, address); // physical
+    put64(file, file_size);
+    put64(file, memory_size);
+    put64(file, PAGE_SIZE);
+}
+
+fn put16(file: &mut Vec<u8>, value: u16) {
+    file.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(file: &mut Vec<u8>, value: u32) {
+    file.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(file: &mut Vec<u8>, value: u64) {
+    file.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{optimize, parse, Dialect, TapeMode};
+
+    fn elf(source: &str, triple: &str) -> Result<Vec<u8>, Error> {
+        let program = parse(source, Dialect::default()).unwrap();
+        let program = optimize(program, TapeMode::Wrap);
+        let triple = Triple::from_str(triple).unwrap();
+        compile_elf(&program, EofBehavior::Zero, TapeConfig::default(), triple)
+    }
+
+    fn read16(file: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(file[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read32(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read64(file: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(file[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn elf_header() {
+        let targets = [
+            ("x86_64-unknown-linux-gnu", 62),
+            ("aarch64-unknown-linux-gnu", 183),
+        ];
+        for (triple, machine) in targets {
+            let file = match elf("++[>+++<-]>.", triple) {
+                Err(Error::DisabledIsa(_)) => continue,
+                result => result.unwrap(),
+            };
+            assert_eq!(&file[..4], b"\x7fELF", "{}", triple);
+            // 64 bit, little endian, executable
+            assert_eq!(file[4], 2, "{}", triple);
+            assert_eq!(file[5], 1, "{}", triple);
+            assert_eq!(read16(&file, 16), 2, "{}", triple);
+            assert_eq!(read16(&file, 18), machine, "{}", triple);
+
+            // the entry point is in an executable PT_LOAD segment
+            let entry = read64(&file, 24);
+            let phoff = read64(&file, 32) as usize;
+            let phentsize = read16(&file, 54) as usize;
+            let phnum = read16(&file, 56) as usize;
+            let contains_entry = (0..phnum).any(|index| {
+                let header = phoff + index * phentsize;
+                let vaddr = read64(&file, header + 16);
+                let memsz = read64(&file, header + 40);
+                read32(&file, header) == 1
+                    && read32(&file, header + 4) & 1 != 0
+                    && (vaddr..vaddr + memsz).contains(&entry)
+            });
+            assert!(contains_entry, "{}", triple);
+        }
+    }
+
+    #[test]
+    fn elf_rejects_other_targets() {
+        for triple in [
+            "riscv64gc-unknown-linux-gnu",
+            "s390x-unknown-linux-gnu",
+            "x86_64-apple-darwin",
+        ] {
+            match elf("+.", triple) {
+                Err(Error::UnsupportedElfTarget(name)) => {
+                    assert_eq!(name, triple)
+                }
+                result => panic!("{}: {:?}", triple, result.map(|_| ())),
+            }
+        }
+    }
+
+    // Runs the programs as executables and compares their output with the
+    // interpreter
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn elf_runs_like_the_interpreter() {
+        use std::{
+            fs,
+            io::Write,
+            os::unix::fs::PermissionsExt,
+            path::Path,
+            process::{Command, Stdio},
+        };
+
+        use crate::{Engine, Interpreter};
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let programs = [
+            fs::read_to_string(dir.join("copy-loop.bf")).unwrap(),
+            fs::read_to_string(dir.join("letter-a.bf")).unwrap(),
+            fs::read_to_string(dir.join("loops.bf")).unwrap(),
+            ",[.,]".to_string(),
+        ];
+        let input = b"hello";
+        let path = std::env::temp_dir()
+            .join(format!("rsbf-elf-test-{}", std::process::id()));
+        for source in programs {
+            let program = parse(&source, Dialect::default()).unwrap();
+            let program = optimize(program, TapeMode::Wrap);
+            let mut expected = vec![];
+            Interpreter::default()
+                .run(&program, &mut &input[..], &mut expected)
+                .unwrap();
+
+            let file = elf(&source, "x86_64-unknown-linux-gnu").unwrap();
+            fs::write(&path, file).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                .unwrap();
+            let mut child = Command::new(&path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            // programs that don't read may be done before this
+            let _ = child.stdin.take().unwrap().write_all(input);
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "{}", source);
+            assert_eq!(output.stdout, expected, "{}", source);
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
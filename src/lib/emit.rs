@@ -0,0 +1,67 @@
+use crate::{
+    ir::{Block, Node},
+    CodePos, TokenKind,
+};
+
+/*
+The tree walk shared by the backends that write source code: llvm, rust, js
+and python. They implement the statements for each kind of token, block calls
+them for every node of the program in order. None of them supports debug
+dumps, Debug tokens are skipped.
+*/
+
+pub(crate) trait Emitter: Sized {
+    // Emits body while the current cell is nonzero
+    fn while_loop(&mut self, code_pos: &CodePos, body: impl FnOnce(&mut Self));
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos);
+
+    // ptr[offset] += delta
+    fn add(&mut self, offset: isize, delta: isize, code_pos: &CodePos);
+
+    // ptr[offset] += *ptr * factor, for Copy and MulAdd
+    fn mul_add(&mut self, offset: isize, factor: isize, code_pos: &CodePos);
+
+    fn clear(&mut self, offset: isize, code_pos: &CodePos);
+
+    fn output(&mut self, offset: isize, code_pos: &CodePos);
+
+    fn input(&mut self, offset: isize, code_pos: &CodePos);
+
+    fn block(&mut self, block: &Block) {
+        for node in block {
+            let token = match node {
+                Node::Loop(inner) => {
+                    self.while_loop(&inner.code_pos, |emitter| {
+                        emitter.block(&inner.body)
+                    });
+                    continue;
+                }
+                Node::Token(token) => token,
+            };
+            let code_pos = &token.code_pos;
+
+            match token.kind {
+                TokenKind::Output { offset } => self.output(offset, code_pos),
+                TokenKind::Input { offset } => self.input(offset, code_pos),
+                TokenKind::Clear { offset } => self.clear(offset, code_pos),
+                TokenKind::ValMod { offset, delta } => {
+                    self.add(offset, delta, code_pos)
+                }
+                TokenKind::PosMod(n) => self.move_by(n, code_pos),
+                TokenKind::Copy(offset) => self.mul_add(offset, 1, code_pos),
+                TokenKind::MulAdd { offset, factor } => {
+                    self.mul_add(offset, factor, code_pos)
+                }
+                TokenKind::Scan(stride) => {
+                    self.while_loop(code_pos, |emitter| {
+                        emitter.move_by(stride, code_pos)
+                    });
+                }
+                TokenKind::Debug { .. }
+                | TokenKind::Bracket(_)
+                | TokenKind::Comment => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::{ir::Block, Error, Limit};
+
+// Something that can run a program, every engine behaves the same for the
+// same settings so they can be swapped freely
+pub trait Engine {
+    // Runs the program with the given input and output, output is flushed
+    // before returning
+    fn run(
+        &self,
+        program: &Block,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<RunStats, Error>;
+}
+
+// Statistics about a single run of a program
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    // Time spent compiling the program, zero if the engine doesn't compile
+    pub compile_time: Duration,
+    // Time spent running the program
+    pub run_time: Duration,
+}
+
+// Calls run with output cut off after limit bytes, if there is a limit.
+// Writing more fails and the error run returns because of it becomes
+// Error::LimitExceeded
+pub(crate) fn with_output_limit<T>(
+    output: &mut dyn Write,
+    limit: Option<u64>,
+    run: impl FnOnce(&mut dyn Write) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return run(output),
+    };
+    let mut limited = LimitedOutput {
+        output,
+        left: limit,
+        exceeded: false,
+    };
+    match run(&mut limited) {
+        Err(Error::Io(_)) if limited.exceeded => {
+            Err(Error::LimitExceeded(Limit::Output(limit)))
+        }
+        result => result,
+    }
+}
+
+struct LimitedOutput<'a> {
+    output: &'a mut dyn Write,
+    // bytes that may still be written
+    left: u64,
+    exceeded: bool,
+}
+
+impl Write for LimitedOutput<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.left == 0 && !buf.is_empty() {
+            self.exceeded = true;
+            return Err(io::Error::other("output limit exceeded"));
+        }
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let written = self.output.write(&buf[..len])?;
+        self.left -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
@@ -0,0 +1,208 @@
+use std::{fmt, io};
+
+#[cfg(feature = "codegen")]
+use cranelift::codegen::{verifier::VerifierErrors, CodegenError};
+#[cfg(feature = "aot")]
+use cranelift_module::ModuleError;
+
+use crate::{
+    BracketState, CodePos, Limit, OUT_OF_BOUNDS_MESSAGE, OUT_OF_MEMORY_MESSAGE,
+    POINTER_OFF_TAPE_MESSAGE,
+};
+
+// Every error the library can return
+#[derive(Debug)]
+pub enum Error {
+    // A bracket without a matching partner, the state tells which one
+    UnbalancedBracket(BracketState, CodePos),
+    // Cranelift failed to compile the generated function
+    #[cfg(feature = "codegen")]
+    Codegen(CodegenError),
+    // The generated function didn't pass the cranelift verifier, this is
+    // always a bug in rsbf
+    #[cfg(feature = "codegen")]
+    Verifier(VerifierErrors),
+    // Declaring or defining something in an object file failed
+    #[cfg(feature = "aot")]
+    Module(Box<ModuleError>),
+    Io(io::Error),
+    // A cell outside of the tape was accessed in TapeMode::Error, or left of
+    // the tape in TapeMode::Grow
+    OutOfBounds(CodePos),
+    // The pointer left the tape in TapeMode::Strict
+    PointerOffTape(CodePos),
+    // Growing the tape in TapeMode::Grow failed
+    OutOfMemory,
+    // No cranelift backend exists for the requested target
+    UnsupportedIsa(String),
+    // The cranelift backend for the requested target wasn't compiled in
+    DisabledIsa(String),
+    // ELF executables can only be written for some of the cranelift targets
+    UnsupportedElfTarget(String),
+    // Cranelift compiled code for an ELF executable that needs relocations,
+    // which there's no linker to apply
+    ElfRelocations(String),
+    // The program ran into one of its Limits
+    LimitExceeded(Limit),
+    // A TapeConfig with a size of 0
+    EmptyTape,
+    // A TapeConfig whose cells don't fit in memory, or in the memory of the
+    // target
+    TapeTooLarge,
+}
+
+impl Error {
+    // Source position the error points at, if any
+    pub fn code_pos(&self) -> Option<&CodePos> {
+        match self {
+            Error::UnbalancedBracket(_, code_pos)
+            | Error::OutOfBounds(code_pos)
+            | Error::PointerOffTape(code_pos) => Some(code_pos),
+            _ => None,
+        }
+    }
+
+    // Renders the error as a diagnostic with the offending source line and
+    // a caret under the column, path is only used for display
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let mut result = format!("error: {}\n", self);
+        let code_pos = match self.code_pos() {
+            Some(code_pos) => code_pos,
+            None => return result,
+        };
+
+        let gutter = " ".repeat(code_pos.line.to_string().len());
+        result += &format!("{}--> {}:{}\n", gutter, path, code_pos);
+        result += &source_snippet(source, code_pos);
+        result
+    }
+}
+
+// The source line at code_pos with a caret under the column, in the style of
+// rustc's diagnostics
+pub(crate) fn source_snippet(source: &str, code_pos: &CodePos) -> String {
+    let line = source.lines().nth(code_pos.line - 1).unwrap_or("");
+    let gutter = " ".repeat(code_pos.line.to_string().len());
+    // keep tabs so the caret lines up with the source line
+    let padding: String = line
+        .chars()
+        .take(code_pos.col - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let mut result = format!("{} |\n", gutter);
+    result += &format!("{} | {}\n", code_pos.line, line);
+    result += &format!("{} | {}^\n", gutter, padding);
+    result
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnbalancedBracket(BracketState::Open, code_pos) => {
+                write!(f, "unmatched '[' at {}", code_pos)
+            }
+            Error::UnbalancedBracket(BracketState::Closed, code_pos) => {
+                write!(f, "unmatched ']' at {}", code_pos)
+            }
+            #[cfg(feature = "codegen")]
+            Error::Codegen(err) => write!(f, "code generation failed: {}", err),
+            #[cfg(feature = "codegen")]
+            Error::Verifier(errors) => {
+                write!(f, "generated code failed verification: {}", errors)
+            }
+            #[cfg(feature = "aot")]
+            Error::Module(err) => {
+                write!(f, "object file generation failed: {}", err)
+            }
+            Error::Io(err) => write!(f, "i/o error: {}", err),
+            Error::OutOfBounds(code_pos) => {
+                write!(f, "{} {}", OUT_OF_BOUNDS_MESSAGE, code_pos)
+            }
+            Error::PointerOffTape(code_pos) => {
+                write!(f, "{} {}", POINTER_OFF_TAPE_MESSAGE, code_pos)
+            }
+            Error::OutOfMemory => write!(f, "{}", OUT_OF_MEMORY_MESSAGE),
+            Error::UnsupportedIsa(triple) => {
+                write!(f, "unsupported target ISA: {}", triple)
+            }
+            Error::DisabledIsa(triple) => write!(
+                f,
+                "support for {} was not compiled in, enable its feature \
+                 (x86, arm64, riscv64 or s390x)",
+                triple
+            ),
+            Error::UnsupportedElfTarget(triple) => write!(
+                f,
+                "ELF output only supports x86_64 and aarch64 linux, not {}",
+                triple
+            ),
+            Error::ElfRelocations(triple) => write!(
+                f,
+                "ELF output for {} needs relocations, try --target native",
+                triple
+            ),
+            Error::LimitExceeded(Limit::Steps(steps)) => {
+                write!(f, "step limit of {} exceeded", steps)
+            }
+            Error::LimitExceeded(Limit::Time(time)) => {
+                write!(f, "time limit of {:?} exceeded", time)
+            }
+            Error::LimitExceeded(Limit::Output(bytes)) => {
+                write!(f, "output limit of {} bytes exceeded", bytes)
+            }
+            Error::EmptyTape => write!(f, "the tape needs at least one cell"),
+            Error::TapeTooLarge => {
+                write!(f, "the tape doesn't fit in the memory of the target")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "codegen")]
+            Error::Codegen(err) => Some(err),
+            #[cfg(feature = "codegen")]
+            Error::Verifier(errors) => Some(errors),
+            #[cfg(feature = "aot")]
+            Error::Module(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(feature = "codegen")]
+impl From<CodegenError> for Error {
+    fn from(err: CodegenError) -> Self {
+        match err {
+            CodegenError::Verifier(errors) => Error::Verifier(errors),
+            err => Error::Codegen(err),
+        }
+    }
+}
+
+#[cfg(feature = "codegen")]
+impl From<VerifierErrors> for Error {
+    fn from(errors: VerifierErrors) -> Self {
+        Error::Verifier(errors)
+    }
+}
+
+#[cfg(feature = "aot")]
+impl From<ModuleError> for Error {
+    fn from(err: ModuleError) -> Self {
+        match err {
+            ModuleError::Compilation(err) => err.into(),
+            err => Error::Module(Box::new(err)),
+        }
+    }
+}
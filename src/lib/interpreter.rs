@@ -0,0 +1,437 @@
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    debug_dump,
+    engine::with_output_limit,
+    ir::{Block, Node},
+    BracketState, Engine, EofBehavior, Error, Limit, Limits, RunStats,
+    TapeConfig, TapeMode, Token, TokenKind,
+};
+
+// Flattens the program tree back into tokens with brackets, jumps holds the
+// index of the matching bracket for every bracket token
+fn flatten(block: &Block, tokens: &mut Vec<Token>, jumps: &mut Vec<usize>) {
+    for node in block {
+        match node {
+            Node::Token(token) => {
+                tokens.push(token.clone());
+                jumps.push(0);
+            }
+            Node::Loop(inner) => {
+                let open = tokens.len();
+                tokens.push(Token {
+                    kind: TokenKind::Bracket(BracketState::Open),
+                    code_pos: inner.code_pos.clone(),
+                    end_pos: inner.code_pos.clone(),
+                });
+                jumps.push(0);
+
+                flatten(&inner.body, tokens, jumps);
+
+                jumps[open] = tokens.len();
+                tokens.push(Token {
+                    kind: TokenKind::Bracket(BracketState::Closed),
+                    code_pos: inner.end_pos.clone(),
+                    end_pos: inner.end_pos.clone(),
+                });
+                jumps.push(open);
+            }
+        }
+    }
+}
+
+// The interpreter's tape, accessing cells outside of it is handled according
+// to the tape mode, cells are truncated to the cell width on every store
+struct Tape {
+    cells: Vec<isize>,
+    mode: TapeMode,
+    mask: isize,
+}
+
+impl Tape {
+    fn new(config: TapeConfig) -> Self {
+        Tape {
+            cells: vec![0; config.size],
+            mode: config.mode,
+            mask: config.cell_width.max() as isize,
+        }
+    }
+
+    #[inline(always)]
+    fn is_zero(&self, index: usize) -> bool {
+        self.cells[index] == 0
+    }
+
+    // Moves mempos by offset cells, only wraps the position around, going
+    // off the tape is checked once a cell is accessed
+    #[inline(always)]
+    fn offset(&self, mempos: usize, offset: isize) -> usize {
+        let index = mempos.wrapping_add(offset as usize);
+        if index < self.cells.len() || self.mode != TapeMode::Wrap {
+            index
+        } else {
+            (mempos as isize + offset).rem_euclid(self.cells.len() as isize)
+                as usize
+        }
+    }
+
+    // Index of the cell offset cells away from mempos
+    #[inline(always)]
+    fn index(
+        &mut self,
+        mempos: usize,
+        offset: isize,
+        token: &Token,
+    ) -> Result<usize, Error> {
+        let index = mempos.wrapping_add(offset as usize);
+        if index < self.cells.len() {
+            Ok(index)
+        } else {
+            self.index_off_tape(mempos, offset, token)
+        }
+    }
+
+    #[cold]
+    fn index_off_tape(
+        &mut self,
+        mempos: usize,
+        offset: isize,
+        token: &Token,
+    ) -> Result<usize, Error> {
+        let index = mempos as isize + offset;
+        match self.mode {
+            TapeMode::Wrap => {
+                Ok(index.rem_euclid(self.cells.len() as isize) as usize)
+            }
+            TapeMode::Grow if index >= 0 => {
+                let index = index as usize;
+                let size = (self.cells.len() * 2).max(index + 1);
+                let additional = size - self.cells.len();
+                if self.cells.try_reserve_exact(additional).is_err() {
+                    return Err(Error::OutOfMemory);
+                }
+                self.cells.resize(size, 0);
+                Ok(index)
+            }
+            // optimize doesn't sink moves in strict mode, so the only cells
+            // off the tape a token reaches are ones the pointer moved to
+            TapeMode::Strict => {
+                Err(Error::PointerOffTape(token.code_pos.clone()))
+            }
+            _ => Err(Error::OutOfBounds(token.code_pos.clone())),
+        }
+    }
+}
+
+// Finds the first zero cell starting at mempos going stride cells at a time,
+// every stride counts as a step
+fn scan(
+    tape: &mut Tape,
+    budget: &mut Option<Budget>,
+    mempos: usize,
+    stride: isize,
+    token: &Token,
+) -> Result<usize, Error> {
+    let is_zero = |cell: &isize| *cell == 0;
+    let mut mempos = mempos;
+    // search the rest of the tape in one go, then continue past its end
+    match stride {
+        1 if mempos < tape.cells.len() => {
+            let found = tape.cells[mempos..].iter().position(is_zero);
+            let strides = found.unwrap_or(tape.cells.len() - mempos);
+            charge(budget, strides as u64)?;
+            match found {
+                Some(_) => return Ok(mempos + strides),
+                None => mempos = tape.cells.len(),
+            }
+        }
+        -1 if mempos < tape.cells.len() => {
+            let found = tape.cells[..=mempos].iter().rposition(is_zero);
+            let strides = found.map_or(mempos + 1, |index| mempos - index);
+            charge(budget, strides as u64)?;
+            match found {
+                Some(index) => return Ok(index),
+                None => mempos = usize::MAX,
+            }
+        }
+        _ => {}
+    }
+
+    // other strides are walked cell by cell, a tape without any zero cell
+    // loops forever here just like the original loop would
+    loop {
+        let index = tape.index(mempos, 0, token)?;
+        if tape.is_zero(index) {
+            return Ok(index);
+        }
+        mempos = tape.offset(index, stride);
+        charge(budget, 1)?;
+    }
+}
+
+// Steps taken so far by a run with limits
+struct Budget {
+    limits: Limits,
+    steps: u64,
+    start: Instant,
+}
+
+// Counts steps against the limits, does nothing for runs without any
+#[inline(always)]
+fn charge(budget: &mut Option<Budget>, steps: u64) -> Result<(), Error> {
+    match budget {
+        Some(budget) => budget.charge(steps),
+        None => Ok(()),
+    }
+}
+
+impl Budget {
+    fn charge(&mut self, steps: u64) -> Result<(), Error> {
+        let before = self.steps;
+        self.steps += steps;
+        if let Some(limit) = self.limits.steps {
+            if self.steps > limit {
+                return Err(Error::LimitExceeded(Limit::Steps(limit)));
+            }
+        }
+        if let Some(time) = self.limits.time {
+            let checks = before / TIME_CHECK_INTERVAL;
+            if (before == 0 || self.steps / TIME_CHECK_INTERVAL != checks)
+                && self.start.elapsed() > time
+            {
+                return Err(Error::LimitExceeded(Limit::Time(time)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads a single byte from input, returns None on EOF
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            // ignore CR from Window's CRLF
+            Ok(_) if cfg!(target_os = "windows") && buf[0] == b'\r' => {}
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Runs the program token by token
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Interpreter {
+    pub eof: EofBehavior,
+    pub tape: TapeConfig,
+    pub limits: Limits,
+}
+
+impl Engine for Interpreter {
+    fn run(
+        &self,
+        program: &Block,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<RunStats, Error> {
+        let start = Instant::now();
+        with_output_limit(output, self.limits.output, |output| {
+            interpret(
+                program,
+                self.eof,
+                self.tape,
+                self.limits,
+                input,
+                output,
+            )?;
+            output.flush()?;
+            Ok(())
+        })?;
+        Ok(RunStats {
+            compile_time: Duration::ZERO,
+            run_time: start.elapsed(),
+        })
+    }
+}
+
+// Steps run between checks of the time limit
+const TIME_CHECK_INTERVAL: u64 = 1 << 16;
+
+fn interpret(
+    program: &Block,
+    eof: EofBehavior,
+    tape_config: TapeConfig,
+    limits: Limits,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut machine = Machine::new(program, eof, tape_config)?;
+    machine.limit(limits);
+    while !machine.is_done() {
+        machine.step(input, output)?;
+    }
+    Ok(())
+}
+
+// A program being interpreted, runs one token at a time so the debugger can
+// stop anywhere in between
+pub(crate) struct Machine {
+    tape: Tape,
+    eof: EofBehavior,
+    pub(crate) mempos: usize,
+    // index of the next token to run
+    pub(crate) pos: usize,
+    pub(crate) tokens: Vec<Token>,
+    // index of the matching bracket for every bracket token
+    pub(crate) jumps: Vec<usize>,
+    budget: Option<Budget>,
+}
+
+impl Machine {
+    pub(crate) fn new(
+        program: &Block,
+        eof: EofBehavior,
+        tape_config: TapeConfig,
+    ) -> Result<Self, Error> {
+        tape_config.validate()?;
+        let mut tokens = vec![];
+        let mut jumps = vec![];
+        flatten(program, &mut tokens, &mut jumps);
+        Ok(Machine {
+            tape: Tape::new(tape_config),
+            eof,
+            mempos: 0,
+            pos: 0,
+            tokens,
+            jumps,
+            budget: None,
+        })
+    }
+
+    // Makes step fail once the step or time limit is exceeded, the output
+    // limit is up to the output passed to step
+    pub(crate) fn limit(&mut self, limits: Limits) {
+        if limits.needs_fuel() {
+            self.budget = Some(Budget {
+                limits,
+                steps: 0,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    // Value of the cell at index as the program sees it, None if it isn't
+    // on the tape (yet)
+    pub(crate) fn cell(&self, index: usize) -> Option<isize> {
+        self.tape.cells.get(index).copied()
+    }
+
+    pub(crate) fn tape_len(&self) -> usize {
+        self.tape.cells.len()
+    }
+
+    // Runs the token at pos
+    #[inline(always)]
+    pub(crate) fn step(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let tape = &mut self.tape;
+        let mempos = self.mempos;
+        let token = &self.tokens[self.pos];
+        charge(&mut self.budget, 1)?;
+        match &token.kind {
+            TokenKind::Output { offset } => {
+                let x = tape.index(mempos, *offset, token)?;
+                output.write_all(&[tape.cells[x] as u8])?;
+                // updates output per char but is much slower in a slow terminal
+                // output.flush()?;
+            }
+            TokenKind::Input { offset } => {
+                let x = tape.index(mempos, *offset, token)?;
+                // make sure prompts are visible before blocking on input
+                output.flush()?;
+                match read_byte(input)? {
+                    Some(value) => tape.cells[x] = value as isize,
+                    None => {
+                        if let Some(value) = self.eof.value() {
+                            tape.cells[x] = value as isize & tape.mask
+                        }
+                    }
+                }
+            }
+            TokenKind::Clear { offset } => {
+                let x = tape.index(mempos, *offset, token)?;
+                tape.cells[x] = 0;
+            }
+            TokenKind::ValMod { offset, delta } => {
+                let x = tape.index(mempos, *offset, token)?;
+                tape.cells[x] = tape.cells[x].wrapping_add(*delta) & tape.mask;
+            }
+            TokenKind::PosMod(value) => {
+                self.mempos = tape.offset(mempos, *value);
+                if tape.mode == TapeMode::Strict {
+                    tape.index(self.mempos, 0, token)?;
+                }
+            }
+            TokenKind::Bracket(BracketState::Open) => {
+                let x = tape.index(mempos, 0, token)?;
+                if tape.is_zero(x) {
+                    self.pos = self.jumps[self.pos];
+                }
+            }
+            TokenKind::Bracket(BracketState::Closed) => {
+                let x = tape.index(mempos, 0, token)?;
+                if !tape.is_zero(x) {
+                    self.pos = self.jumps[self.pos];
+                }
+            }
+            TokenKind::Copy(offset) => {
+                let from = tape.index(mempos, 0, token)?;
+                if !tape.is_zero(from) {
+                    let x = tape.index(mempos, *offset, token)?;
+                    tape.cells[x] = tape.cells[x]
+                        .wrapping_add(tape.cells[from])
+                        & tape.mask;
+                }
+            }
+            TokenKind::MulAdd { offset, factor } => {
+                let from = tape.index(mempos, 0, token)?;
+                if !tape.is_zero(from) {
+                    let x = tape.index(mempos, *offset, token)?;
+                    let value = tape.cells[from].wrapping_mul(*factor);
+                    tape.cells[x] =
+                        tape.cells[x].wrapping_add(value) & tape.mask;
+                }
+            }
+            TokenKind::Scan(stride) => {
+                self.mempos =
+                    scan(tape, &mut self.budget, mempos, *stride, token)?;
+            }
+            TokenKind::Debug { radius } => {
+                // keep the dump in order with the output before it
+                output.flush()?;
+                let line = debug_dump(
+                    mempos as isize,
+                    *radius,
+                    tape.cells.len(),
+                    |index| tape.cells[index] as u32,
+                );
+                io::stderr().write_all(line.as_bytes())?;
+            }
+            TokenKind::Comment => {}
+        }
+        self.pos += 1;
+        Ok(())
+    }
+}
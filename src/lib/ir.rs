@@ -0,0 +1,53 @@
+use crate::{validate, BracketState, CodePos, Error, Token, TokenKind};
+
+// A sequence of nodes executed in order
+pub type Block = Vec<Node>;
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    // Any token except brackets
+    Token(Token),
+    Loop(Loop),
+}
+
+// while (*ptr) { body }
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub body: Block,
+    // Position of the opening bracket
+    pub code_pos: CodePos,
+    // Position of the closing bracket
+    pub end_pos: CodePos,
+}
+
+// Builds a tree from tokenize output, brackets are turned into loops so the
+// result is balanced by construction
+pub fn build(tokens: Vec<Token>) -> Result<Block, Vec<Error>> {
+    validate(&tokens)?;
+
+    // blocks that are still open and the position of the bracket that opened
+    // them, the outermost one is the program itself
+    let mut stack: Vec<(Block, Option<CodePos>)> = vec![(vec![], None)];
+
+    for token in tokens {
+        match token.kind {
+            TokenKind::Bracket(BracketState::Open) => {
+                stack.push((vec![], Some(token.code_pos)))
+            }
+            TokenKind::Bracket(BracketState::Closed) => {
+                // validate makes sure both of these exist
+                let (body, code_pos) = stack.pop().unwrap();
+                let parent = &mut stack.last_mut().unwrap().0;
+                parent.push(Node::Loop(Loop {
+                    body,
+                    code_pos: code_pos.unwrap(),
+                    end_pos: token.end_pos,
+                }));
+            }
+            TokenKind::Comment => {}
+            _ => stack.last_mut().unwrap().0.push(Node::Token(token)),
+        }
+    }
+
+    Ok(stack.pop().unwrap().0)
+}
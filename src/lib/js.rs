@@ -0,0 +1,226 @@
+use crate::{
+    emit::Emitter, ir::Block, CellWidth, CodePos, EofBehavior, Error,
+    TapeConfig, TapeMode,
+};
+
+/*
+Translates to a javascript file that runs in node and browsers. The program
+is `run(input, output)`, input returns the next byte or null (or undefined)
+at the end of input and output is called with every byte written. Typed
+arrays wrap values on store, so only multiplication needs Math.imul to stay
+exact for 32 bit cells. When the file is run directly by node, stdin and
+stdout are used.
+*/
+
+// Translates a program tree to javascript
+pub fn js_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+) -> Result<String, Error> {
+    tape.validate()?;
+    let array = match tape.cell_width {
+        CellWidth::U8 => "Uint8Array",
+        CellWidth::U16 => "Uint16Array",
+        CellWidth::U32 => "Uint32Array",
+    };
+
+    let cell_fn = match tape.mode {
+        TapeMode::Wrap => {
+            "function cell(index, line, col) {
+    return ((index % SIZE) + SIZE) % SIZE;
+  }"
+        }
+        TapeMode::Error | TapeMode::Strict => {
+            "function cell(index, line, col) {
+    if (index < 0 || index >= SIZE) throw outOfBounds(line, col);
+    return index;
+  }"
+        }
+        TapeMode::Grow => {
+            "function cell(index, line, col) {
+    if (index < 0) throw outOfBounds(line, col);
+    if (index >= tape.length) {
+      const grown = new Cells(Math.max(tape.length * 2, index + 1));
+      grown.set(tape);
+      tape = grown;
+    }
+    return index;
+  }"
+        }
+    };
+
+    let mut result = format!(
+        "// Generated by rsbfc
+\"use strict\";
+
+const Cells = {};
+const SIZE = {};
+
+function outOfBounds(line, col) {{
+  return new Error(`{} ${{line}}:${{col}}`);
+}}
+
+// input() returns a byte or null at the end of input, output(byte) writes one
+function run(input, output) {{
+  let tape = new Cells(SIZE);
+  let pos = 0;
+  // index and value of the cell being worked on, the index is found before
+  // tape is used as cell can replace it
+  let i = 0, value = 0;
+
+  // index of the cell at index, handles cells outside of the tape
+  {}
+
+",
+        array,
+        tape.size,
+        tape.mode.off_tape_message(),
+        cell_fn
+    );
+
+    let mut translator = Translator {
+        result: &mut result,
+        indent: 1,
+        tape,
+        eof,
+    };
+    translator.block(block);
+
+    Ok(result
+        + "}
+
+if (typeof module !== \"undefined\") {
+  module.exports = { run };
+}
+
+// stdin and stdout when run by node, output is buffered until input is read
+if (typeof require !== \"undefined\" && require.main === module) {
+  const fs = require(\"fs\");
+  let buffered = [];
+  const flush = () => {
+    if (buffered.length > 0) fs.writeSync(1, Buffer.from(buffered));
+    buffered = [];
+  };
+  const input = () => {
+    flush();
+    const byte = Buffer.alloc(1);
+    for (;;) {
+      try {
+        return fs.readSync(0, byte, 0, 1, null) === 0 ? null : byte[0];
+      } catch (err) {
+        if (err.code === \"EOF\") return null;
+        if (err.code !== \"EAGAIN\") throw err;
+      }
+    }
+  };
+  try {
+    run(input, (byte) => buffered.push(byte));
+    flush();
+  } catch (err) {
+    flush();
+    process.stderr.write(`error: ${err.message}\\n`);
+    process.exit(1);
+  }
+}
+")
+}
+
+struct Translator<'a> {
+    result: &'a mut String,
+    indent: usize,
+    tape: &'a TapeConfig,
+    eof: EofBehavior,
+}
+
+impl Translator<'_> {
+    fn line(&mut self, line: &str) {
+        *self.result += &"  ".repeat(self.indent);
+        *self.result += line;
+        *self.result += "\n";
+    }
+
+    // Expression for the index of the cell at pos + offset
+    fn cell(&self, offset: isize, code_pos: &CodePos) -> String {
+        let index = match offset {
+            0 => "pos".to_string(),
+            _ if offset < 0 => format!("pos - {}", -offset),
+            _ => format!("pos + {}", offset),
+        };
+        format!("cell({}, {}, {})", index, code_pos.line, code_pos.col)
+    }
+
+    // Sets i to the index of the cell at pos + offset, then adds line
+    fn with_cell(&mut self, offset: isize, code_pos: &CodePos, line: &str) {
+        let line = format!("i = {}; {}", self.cell(offset, code_pos), line);
+        self.line(&line);
+    }
+}
+
+impl Emitter for Translator<'_> {
+    fn while_loop(&mut self, code_pos: &CodePos, body: impl FnOnce(&mut Self)) {
+        let line = format!(
+            "while ((i = {}, tape[i]) !== 0) {{",
+            self.cell(0, code_pos)
+        );
+        self.line(&line);
+        self.indent += 1;
+        body(self);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos) {
+        match self.tape.mode {
+            TapeMode::Wrap | TapeMode::Strict => {
+                let line = format!("pos = {};", self.cell(n, code_pos));
+                self.line(&line)
+            }
+            _ if n < 0 => self.line(&format!("pos -= {};", -n)),
+            _ => self.line(&format!("pos += {};", n)),
+        }
+    }
+
+    fn add(&mut self, offset: isize, delta: isize, code_pos: &CodePos) {
+        let max = self.tape.cell_width.max() as isize;
+        let line = format!("tape[i] += {};", delta & max);
+        self.with_cell(offset, code_pos, &line);
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize, code_pos: &CodePos) {
+        let max = self.tape.cell_width.max() as isize;
+        let value = match factor {
+            1 => "value".to_string(),
+            _ => format!("Math.imul(value, {})", factor & max),
+        };
+        self.with_cell(0, code_pos, "value = tape[i];");
+        let add = format!("tape[i] += {};", value);
+        match self.tape.mode {
+            TapeMode::Wrap => self.with_cell(offset, code_pos, &add),
+            _ => {
+                self.line("if (value !== 0) {");
+                self.indent += 1;
+                self.with_cell(offset, code_pos, &add);
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+
+    fn clear(&mut self, offset: isize, code_pos: &CodePos) {
+        self.with_cell(offset, code_pos, "tape[i] = 0;")
+    }
+
+    fn output(&mut self, offset: isize, code_pos: &CodePos) {
+        self.with_cell(offset, code_pos, "output(tape[i] & 0xff);")
+    }
+
+    fn input(&mut self, offset: isize, code_pos: &CodePos) {
+        self.with_cell(offset, code_pos, "value = input();");
+        self.line(match self.eof {
+            EofBehavior::Unchanged => "if (value != null) tape[i] = value;",
+            EofBehavior::Zero => "tape[i] = value == null ? 0 : value;",
+            EofBehavior::MinusOne => "tape[i] = value == null ? -1 : value;",
+        });
+    }
+}
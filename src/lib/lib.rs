@@ -1,5 +1,35 @@
+#[cfg(feature = "aot")]
+pub mod aot;
 #[cfg(feature = "codegen")]
 pub mod codegen;
+mod debugger;
+#[cfg(feature = "aot")]
+pub mod elf;
+mod emit;
+mod engine;
+mod error;
+mod interpreter;
+pub mod ir;
+mod js;
+mod llvm;
+mod profiler;
+mod python;
+mod rust;
+mod wasm;
+
+use std::{fmt, str::FromStr, time::Duration};
+
+pub use debugger::Debugger;
+pub use engine::{Engine, RunStats};
+pub use error::Error;
+pub use interpreter::Interpreter;
+use ir::{Block, Loop, Node};
+pub use js::js_translate;
+pub use llvm::llvm_translate;
+pub use profiler::{LoopProfile, Profile, Profiler};
+pub use python::python_translate;
+pub use rust::rust_translate;
+pub use wasm::wasm_translate;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum BracketState {
@@ -9,255 +39,1135 @@ pub enum BracketState {
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum TokenKind {
-    ValMod(isize),
+    // ptr[offset] += delta
+    ValMod { offset: isize, delta: isize },
     PosMod(isize),
+    // only in tokenize output, the program tree has loops instead
     Bracket(BracketState),
     Comment,
-    Output,
-    Input,
-    Clear,
+    // putchar(ptr[offset])
+    Output { offset: isize },
+    // ptr[offset] = getchar()
+    Input { offset: isize },
+    // ptr[offset] = 0
+    Clear { offset: isize },
+    // ptr[offset] += *ptr, skipped if *ptr is zero unless the tape wraps. The
+    // loop this replaced wouldn't have run then, ptr[offset] may not even be
+    // on the tape
     Copy(isize),
+    // ptr[offset] += *ptr * factor, skipped like Copy
+    MulAdd { offset: isize, factor: isize },
+    // while (*ptr) { ptr += stride; }
+    Scan(isize),
+    // prints the cells up to radius away from ptr to stderr, only the
+    // interpreter, the JIT and the C backend do, the others skip it
+    Debug { radius: usize },
 }
 impl TokenKind {
     fn from(input: char) -> TokenKind {
         match input {
-            '+' => TokenKind::ValMod(1),
-            '-' => TokenKind::ValMod(-1),
+            '+' => TokenKind::ValMod {
+                offset: 0,
+                delta: 1,
+            },
+            '-' => TokenKind::ValMod {
+                offset: 0,
+                delta: -1,
+            },
             '>' => TokenKind::PosMod(1),
             '<' => TokenKind::PosMod(-1),
             '[' => TokenKind::Bracket(BracketState::Open),
             ']' => TokenKind::Bracket(BracketState::Closed),
-            '.' => TokenKind::Output,
-            ',' => TokenKind::Input,
+            '.' => TokenKind::Output { offset: 0 },
+            ',' => TokenKind::Input { offset: 0 },
 
             _ => TokenKind::Comment,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+// What happens to the current cell when `,` is executed at end of input
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum EofBehavior {
+    Unchanged,
+    #[default]
+    Zero,
+    MinusOne,
+}
+impl EofBehavior {
+    // Value written to the cell on EOF, None leaves the cell untouched,
+    // truncated to the cell width when stored
+    pub fn value(&self) -> Option<u32> {
+        match self {
+            EofBehavior::Unchanged => None,
+            EofBehavior::Zero => Some(0),
+            EofBehavior::MinusOne => Some(u32::MAX),
+        }
+    }
+}
+impl FromStr for EofBehavior {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "unchanged" => Ok(EofBehavior::Unchanged),
+            "zero" | "0" => Ok(EofBehavior::Zero),
+            "minus-one" | "-1" => Ok(EofBehavior::MinusOne),
+            _ => Err(format!(
+                "invalid EOF behavior '{}', expected unchanged, zero or minus-one",
+                input
+            )),
+        }
+    }
+}
+
+// What happens when a cell outside of the tape is accessed
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum TapeMode {
+    // Wrap around to the other end of the tape, moves wrap the pointer too
+    #[default]
+    Wrap,
+    // Stop the program with Error::OutOfBounds
+    Error,
+    // Like Error, moving the pointer off the tape is an error too, stops the
+    // program with Error::PointerOffTape instead. Every move checks where the
+    // pointer ends up, see optimize for why that covers the cells in between
+    Strict,
+    // Grow the tape to the right, moving off the left end is still an error
+    Grow,
+}
+// Messages of the errors generated code reports itself, Error's Display uses
+// them too. The off tape ones are followed by the position
+pub(crate) const OUT_OF_BOUNDS_MESSAGE: &str =
+    "cell outside of the tape accessed at";
+pub(crate) const POINTER_OFF_TAPE_MESSAGE: &str =
+    "pointer moved off the tape at";
+pub(crate) const OUT_OF_MEMORY_MESSAGE: &str = "out of memory";
+
+impl TapeMode {
+    // What leaving the tape is reported as in this mode
+    pub(crate) fn off_tape_message(&self) -> &'static str {
+        match self {
+            TapeMode::Strict => POINTER_OFF_TAPE_MESSAGE,
+            _ => OUT_OF_BOUNDS_MESSAGE,
+        }
+    }
+}
+impl FromStr for TapeMode {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "wrap" => Ok(TapeMode::Wrap),
+            "error" => Ok(TapeMode::Error),
+            "strict" => Ok(TapeMode::Strict),
+            "grow" => Ok(TapeMode::Grow),
+            _ => Err(format!(
+                "invalid tape mode '{}', expected wrap, error, strict or grow",
+                input
+            )),
+        }
+    }
+}
+
+// Tape settings shared by all engines
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TapeConfig {
+    // Amount of cells, the initial amount when growing
+    pub size: usize,
+    pub mode: TapeMode,
+    pub cell_width: CellWidth,
+}
+impl TapeConfig {
+    pub fn new(
+        size: usize,
+        mode: TapeMode,
+        cell_width: CellWidth,
+    ) -> Result<Self, Error> {
+        let tape = TapeConfig {
+            size,
+            mode,
+            cell_width,
+        };
+        tape.validate()?;
+        Ok(tape)
+    }
+
+    // Checked by every engine and translator before using the config
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.size == 0 {
+            return Err(Error::EmptyTape);
+        }
+        match self.size.checked_mul(self.cell_width.bytes()) {
+            Some(bytes) if bytes <= isize::MAX as usize => Ok(()),
+            _ => Err(Error::TapeTooLarge),
+        }
+    }
+
+    // Size of the tape in bytes, validate makes sure this doesn't overflow
+    pub fn bytes(&self) -> usize {
+        self.size * self.cell_width.bytes()
+    }
+}
+impl Default for TapeConfig {
+    fn default() -> Self {
+        TapeConfig {
+            size: 30000,
+            mode: TapeMode::Wrap,
+            cell_width: CellWidth::U8,
+        }
+    }
+}
+
+// Limits for running untrusted programs, a run that hits one stops with
+// Error::LimitExceeded. None is unlimited
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Limits {
+    // Tokens run, brackets included, plus one for every stride a Scan token
+    // moves
+    pub steps: Option<u64>,
+    // Wall clock time spent running
+    pub time: Option<Duration>,
+    // Bytes written to the output, debug dumps don't count
+    pub output: Option<u64>,
+}
+impl Limits {
+    // True if steps or time are limited, the JIT has to count the tokens it
+    // runs for those
+    pub fn needs_fuel(&self) -> bool {
+        self.steps.is_some() || self.time.is_some()
+    }
+}
+
+// The limit a run hit
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    Output(u64),
+}
+
+// Size of a cell, cell arithmetic wraps around at this width
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+impl CellWidth {
+    // Amount of bytes in a cell
+    pub fn bytes(&self) -> usize {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+        }
+    }
+
+    // Largest value a cell can hold, also the mask to truncate values with
+    pub fn max(&self) -> u32 {
+        match self {
+            CellWidth::U8 => u8::MAX as u32,
+            CellWidth::U16 => u16::MAX as u32,
+            CellWidth::U32 => u32::MAX,
+        }
+    }
+}
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "8" | "u8" => Ok(CellWidth::U8),
+            "16" | "u16" => Ok(CellWidth::U16),
+            "32" | "u32" => Ok(CellWidth::U32),
+            _ => Err(format!(
+                "invalid cell width '{}', expected 8, 16 or 32",
+                input
+            )),
+        }
+    }
+}
+
+// Position of a character in the source, line and col start at 1 and col
+// counts characters, offset is the byte offset from the start of the source
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct CodePos {
     pub line: usize,
     pub col: usize,
+    pub offset: usize,
+}
+impl fmt::Display for CodePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
+    // Position of the first character the token was created from
     pub code_pos: CodePos,
+    // Position of the last character the token was created from, equal to
+    // code_pos unless the token was merged by optimize
+    pub end_pos: CodePos,
+}
+impl Token {
+    // Byte range of the source the token was created from
+    pub fn span(&self) -> std::ops::Range<usize> {
+        self.code_pos.offset..self.end_pos.offset + 1
+    }
+}
+
+// Extensions of the language, the characters they use are comments unless
+// they are enabled
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Dialect {
+    // `#` dumps the cells up to this many away from the pointer to stderr
+    pub debug_dump: Option<usize>,
 }
 
 // Translates input string to Vec<Token>
-pub fn tokenize(input: &str) -> Vec<Token> {
+pub fn tokenize(input: &str, dialect: Dialect) -> Vec<Token> {
     let mut tokens: Vec<Token> = vec![];
 
     let mut line = 1;
     let mut col = 1;
-    for command in input.chars() {
+    for (offset, command) in input.char_indices() {
+        let code_pos = CodePos { line, col, offset };
+
+        // every character takes up a column, comments included
         if command == '\n' {
             line += 1;
             col = 1
+        } else {
+            col += 1
         }
 
-        let kind = TokenKind::from(command);
-        let code_pos = CodePos { line, col };
+        let kind = match (command, dialect.debug_dump) {
+            ('#', Some(radius)) => TokenKind::Debug { radius },
+            _ => TokenKind::from(command),
+        };
 
         if kind == TokenKind::Comment {
             continue;
         };
 
-        tokens.push(Token { kind, code_pos });
-        col += 1;
+        tokens.push(Token {
+            kind,
+            end_pos: code_pos.clone(),
+            code_pos,
+        });
     }
 
     tokens
 }
 
-// Optimizes input
-pub fn optimize(input: Vec<Token>) -> Vec<Token> {
-    let mut pos = 0usize;
-    let mut tokens: Vec<Token> = input;
+// Checks that every bracket has a matching partner, returns an error for
+// each unmatched bracket
+pub fn validate(tokens: &[Token]) -> Result<(), Vec<Error>> {
+    let mut errors = vec![];
+    let mut open_brackets: Vec<&Token> = vec![];
 
-    // Optimized multiple adds into one. Example: i++;i++;i++; becomes i+=3;
-    {
-        while pos < tokens.len() - 1 {
-            let token = &tokens[pos];
-            let next = &tokens[pos + 1];
-            match (&token.kind, &next.kind) {
-                (
-                    TokenKind::PosMod(token_value),
-                    TokenKind::PosMod(next_value),
-                ) => {
-                    tokens[pos].kind =
-                        TokenKind::PosMod(token_value + next_value);
-                    tokens.remove(pos + 1);
-                }
-                (
-                    TokenKind::ValMod(token_value),
-                    TokenKind::ValMod(next_value),
-                ) => {
-                    tokens[pos].kind =
-                        TokenKind::ValMod(token_value + next_value);
-                    tokens.remove(pos + 1);
-                }
-                _ => {
-                    pos += 1;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Bracket(BracketState::Open) => open_brackets.push(token),
+            TokenKind::Bracket(BracketState::Closed) => {
+                if open_brackets.pop().is_some() {
+                    continue;
                 }
+                errors.push(Error::UnbalancedBracket(
+                    BracketState::Closed,
+                    token.code_pos.clone(),
+                ));
             }
+            _ => {}
         }
     }
 
-    // Replace while (*ptr) {*ptr += -1} with *ptr = 0;
-    {
-        pos = 0;
-        while pos < tokens.len() - 2 {
-            let tokens_for_check = &tokens[pos..pos + 3];
-            if (tokens_for_check[0].kind
-                == TokenKind::Bracket(BracketState::Open))
-                && (tokens_for_check[1].kind == TokenKind::ValMod(-1))
-                && (tokens_for_check[2].kind
-                    == TokenKind::Bracket(BracketState::Closed))
-            {
-                let code_pos = &tokens_for_check[0].code_pos;
-                tokens.splice(
-                    pos..pos + 3,
-                    [Token {
-                        kind: TokenKind::Clear,
-                        code_pos: code_pos.clone(),
-                    }],
-                );
+    errors.extend(open_brackets.into_iter().map(|token| {
+        Error::UnbalancedBracket(BracketState::Open, token.code_pos.clone())
+    }));
+    // report in source order
+    errors.sort_by_key(|err| err.code_pos().map(|code_pos| code_pos.offset));
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Tokenizes input and builds the program tree
+pub fn parse(input: &str, dialect: Dialect) -> Result<Block, Vec<Error>> {
+    ir::build(tokenize(input, dialect))
+}
+
+// The line a debug dump prints, cell gives the value of a cell on the tape.
+// Cells off the tape are left out, the pointer may not even be on it
+pub(crate) fn debug_dump(
+    pointer: isize,
+    radius: usize,
+    len: usize,
+    cell: impl Fn(usize) -> u32,
+) -> String {
+    let radius = isize::try_from(radius).unwrap_or(isize::MAX);
+    let first = pointer.saturating_sub(radius).max(0);
+    let last = pointer.saturating_add(radius).min(len as isize - 1);
+    let mut line = format!("# pointer {}, cells", pointer);
+    if first > last {
+        line += " none";
+    } else {
+        line += &format!(" {}-{}:", first, last);
+        for index in first..=last {
+            let value = cell(index as usize);
+            if index == pointer {
+                line += &format!(" [{}]", value);
+            } else {
+                line += &format!(" {}", value);
             }
-            pos += 1;
         }
     }
+    line + "\n"
+}
 
-    // Copy loops
-    {
-        let mut stage = 0;
-        let mut start_code_pos = CodePos { line: 1, col: 1 };
-        let mut tokens_optimized = 0;
-        let mut should_clear = false;
-        let mut current_pos_offset = 0;
-        let mut copy_offsets: Vec<isize> = vec![];
-        pos = 0;
-        while pos < tokens.len() {
-            let token = &tokens[pos];
-            match stage {
-                0 => {
-                    if token.kind == TokenKind::Bracket(BracketState::Open) {
-                        start_code_pos = token.code_pos.clone();
-                        stage += 1;
-                        tokens_optimized += 1;
-                        pos += 1;
-                    } else {
-                        pos += 1;
-                    }
-                }
-                1 => {
-                    if token.kind == TokenKind::ValMod(-1) {
-                        should_clear = true;
-                        stage += 1;
-                        tokens_optimized += 1;
-                        pos += 1;
-                    } else {
-                        stage += 1;
-                    }
-                }
-                2 => {
-                    if let TokenKind::PosMod(value) = token.kind {
-                        current_pos_offset += value;
-                        stage += 1;
-                        tokens_optimized += 1;
-                        pos += 1;
-                    } else {
-                        stage = 100;
-                    }
-                }
-                3 => {
-                    if token.kind == TokenKind::ValMod(1) {
-                        copy_offsets.push(current_pos_offset);
-                        stage = 2;
-                        tokens_optimized += 1;
-                        pos += 1;
-                    } else if token.kind
-                        == TokenKind::Bracket(BracketState::Closed)
-                        && current_pos_offset == 0
-                    {
-                        tokens.drain((pos - tokens_optimized)..(pos + 1));
-
-                        for copy_offset in &copy_offsets {
-                            tokens.insert(
-                                pos - tokens_optimized,
-                                Token {
-                                    kind: TokenKind::Copy(*copy_offset),
-                                    code_pos: start_code_pos.clone(),
-                                },
-                            )
-                        }
-
-                        pos = pos - tokens_optimized + copy_offsets.len();
-
-                        if should_clear {
-                            tokens.insert(
-                                pos,
-                                Token {
-                                    kind: TokenKind::Clear,
-                                    code_pos: start_code_pos.clone(),
-                                },
-                            );
-                            pos += 1;
-                        }
-
-                        stage = 100;
-                    } else {
-                        stage = 100;
+// Optimizes input for running with mode. In TapeMode::Strict moves are only
+// merged while they go the same way and aren't sunk, so checking where a move
+// ends covers every cell the pointer passed
+pub fn optimize(input: Block, mode: TapeMode) -> Block {
+    let mut block: Block = Vec::with_capacity(input.len());
+    let strict = mode == TapeMode::Strict;
+
+    for node in input {
+        let token = match node {
+            Node::Loop(mut inner) => {
+                inner.body = optimize(inner.body, mode);
+                match optimize_loop(&inner, mode) {
+                    Ok(tokens) => {
+                        block.extend(tokens.into_iter().map(Node::Token))
                     }
+                    Err(_) => block.push(Node::Loop(inner)),
                 }
-                100 => {
-                    // reset
-                    stage = 0;
-                    start_code_pos = CodePos { line: 1, col: 1 };
-                    tokens_optimized = 0;
-                    should_clear = false;
-                    current_pos_offset = 0;
-                    copy_offsets = vec![];
-                    pos += 1;
+                continue;
+            }
+            Node::Token(token) => token,
+        };
+
+        // Optimized multiple adds into one. Example: i++;i++;i++; becomes i+=3;
+        if let Some(Node::Token(last)) = block.last_mut() {
+            let merged = match (&last.kind, &token.kind) {
+                (TokenKind::PosMod(last_value), TokenKind::PosMod(value))
+                    if !strict || last_value.signum() == value.signum() =>
+                {
+                    Some(TokenKind::PosMod(last_value + value))
                 }
-                _ => {}
+                (
+                    TokenKind::ValMod {
+                        offset: last_offset,
+                        delta: last_delta,
+                    },
+                    TokenKind::ValMod { offset, delta },
+                ) if last_offset == offset => Some(TokenKind::ValMod {
+                    offset: *offset,
+                    delta: last_delta + delta,
+                }),
+                _ => None,
+            };
+            if let Some(kind) = merged {
+                last.kind = kind;
+                last.end_pos = token.end_pos;
+                continue;
             }
         }
+
+        block.push(Node::Token(token));
     }
 
     // note: this site has lots of cool optimizations http://calmerthanyouare.org/2015/01/07/optimizing-brainfuck.html
 
-    tokens
+    if strict {
+        block
+    } else {
+        sink_moves(block)
+    }
 }
 
-// Translates Vec<Token> to C
-pub fn c_translate(tokens: Vec<Token>) -> String {
-    let mut result =
-        String::from("#include <stdio.h>\nint main(){char array[30000] = {0}; char *ptr = array;");
+// Turns pointer moves into cell offsets of the following tokens, only a
+// single move is left at the end of each basic block. Example: ptr++; *ptr++;
+// ptr--; becomes ptr[1]++;
+fn sink_moves(input: Block) -> Block {
+    let mut block: Block = Vec::with_capacity(input.len());
+    let mut offset = 0;
+    // span of the moves that haven't been emitted yet
+    let mut move_span: Option<(CodePos, CodePos)> = None;
 
-    for token in tokens {
-        result += &match token.kind {
-            TokenKind::Output => "putchar(*ptr);".into(),
-            TokenKind::Input => "*ptr = getchar();".into(),
-            TokenKind::Clear => "*ptr = 0;".into(),
-            TokenKind::ValMod(value) => {
-                format!("*ptr += {};", value)
+    for node in input {
+        let mut token = match node {
+            Node::Token(token) => token,
+            // loops are basic block boundaries
+            Node::Loop(_) => {
+                flush_move(&mut block, &mut offset, &mut move_span);
+                block.push(node);
+                continue;
             }
+        };
+
+        match &mut token.kind {
             TokenKind::PosMod(value) => {
-                format!("ptr += {};", value)
+                offset += *value;
+                move_span = match move_span {
+                    Some((code_pos, _)) => Some((code_pos, token.end_pos)),
+                    None => Some((token.code_pos, token.end_pos)),
+                };
+                continue;
+            }
+            TokenKind::ValMod {
+                offset: token_offset,
+                ..
+            }
+            | TokenKind::Output {
+                offset: token_offset,
+            }
+            | TokenKind::Input {
+                offset: token_offset,
+            }
+            | TokenKind::Clear {
+                offset: token_offset,
+            } => *token_offset += offset,
+            // these read the current cell, so the pointer has to be there
+            _ => flush_move(&mut block, &mut offset, &mut move_span),
+        }
+
+        block.push(Node::Token(token));
+    }
+    flush_move(&mut block, &mut offset, &mut move_span);
+
+    block
+}
+
+// Emits the moves sink_moves has collected so far as a single PosMod
+fn flush_move(
+    block: &mut Block,
+    offset: &mut isize,
+    move_span: &mut Option<(CodePos, CodePos)>,
+) {
+    if let Some((code_pos, end_pos)) = move_span.take() {
+        if *offset != 0 {
+            block.push(Node::Token(Token {
+                kind: TokenKind::PosMod(*offset),
+                code_pos,
+                end_pos,
+            }));
+        }
+    }
+    *offset = 0;
+}
+
+// Replaces a loop with straight line tokens if possible, the loop body has to
+// be optimized already for the same mode. Otherwise returns why it can't be
+// replaced
+pub(crate) fn optimize_loop(
+    inner: &Loop,
+    mode: TapeMode,
+) -> Result<Vec<Token>, &'static str> {
+    let make_token = |kind| Token {
+        kind,
+        code_pos: inner.code_pos.clone(),
+        end_pos: inner.end_pos.clone(),
+    };
+
+    // Scan loops: while (*ptr) {ptr += n;}
+    if let [Node::Token(Token {
+        kind: TokenKind::PosMod(stride),
+        ..
+    })] = inner.body.as_slice()
+    {
+        return Ok(vec![make_token(TokenKind::Scan(*stride))]);
+    }
+
+    // Only loops with adds and moves can be replaced, collect the total
+    // change for every cell relative to the loop cell in order of appearance
+    let mut deltas: Vec<(isize, isize)> = vec![];
+    let mut offset = 0;
+    for node in &inner.body {
+        match node {
+            // moves aren't sunk in strict mode, the tokens replacing the loop
+            // wouldn't check where the pointer went
+            Node::Token(Token {
+                kind: TokenKind::PosMod(_),
+                ..
+            }) if mode == TapeMode::Strict => {
+                return Err("moves the pointer in strict mode")
+            }
+            Node::Token(Token {
+                kind: TokenKind::PosMod(value),
+                ..
+            }) => offset += value,
+            Node::Token(Token {
+                kind:
+                    TokenKind::ValMod {
+                        offset: token_offset,
+                        delta: value,
+                    },
+                ..
+            }) => {
+                let offset = offset + token_offset;
+                match deltas.iter_mut().find(|(o, _)| *o == offset) {
+                    Some((_, delta)) => *delta += value,
+                    None => deltas.push((offset, *value)),
+                }
+            }
+            Node::Token(Token {
+                kind:
+                    TokenKind::Output { .. }
+                    | TokenKind::Input { .. }
+                    | TokenKind::Debug { .. },
+                ..
+            }) => return Err("does I/O"),
+            // everything else was a loop before
+            _ => return Err("contains another loop"),
+        }
+    }
+
+    // The loop has to end where it started and decrement the loop cell by one
+    // each iteration, so it runs exactly *ptr times
+    if offset != 0 {
+        return Err("moves the pointer");
+    }
+    match deltas.iter().find(|(o, _)| *o == 0) {
+        Some((_, -1)) => {}
+        _ => return Err("doesn't decrement the loop cell by one"),
+    }
+    // a cell that ends up unchanged gets no token, so it would no longer be
+    // checked for being on the tape
+    if mode != TapeMode::Wrap && deltas.iter().any(|(_, delta)| *delta == 0) {
+        return Err("touches a cell without changing it");
+    }
+
+    // Replace while (*ptr) {*ptr += -1} with *ptr = 0;
+    // Multiply-add loops: while (*ptr) {*ptr += -1; ptr[n] += m; ...} with
+    // ptr[n] += *ptr * m; ...; *ptr = 0;
+    let mut tokens: Vec<Token> = deltas
+        .into_iter()
+        .filter(|(offset, factor)| *offset != 0 && *factor != 0)
+        .map(|(offset, factor)| match factor {
+            1 => make_token(TokenKind::Copy(offset)),
+            _ => make_token(TokenKind::MulAdd { offset, factor }),
+        })
+        .collect();
+    tokens.push(make_token(TokenKind::Clear { offset: 0 }));
+    Ok(tokens)
+}
+
+// Translates a program tree to C, every cell access goes through cell()
+// which handles the tape mode
+pub fn c_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+) -> Result<String, Error> {
+    tape.validate()?;
+    let out_of_bounds = C_FAIL_AT.replace("{MESSAGE}", OUT_OF_BOUNDS_MESSAGE);
+    let out_of_memory = C_FAIL.replace("{MESSAGE}", OUT_OF_MEMORY_MESSAGE);
+    let off_tape = match tape.mode {
+        TapeMode::Wrap => "index %= size; if (index < 0) index += size;",
+        TapeMode::Error | TapeMode::Strict => C_FAIL_AT,
+        TapeMode::Grow => "if (index < 0) {OUT_OF_BOUNDS} long new_size = size * 2 > index ? size * 2 : index + 1; cell_t *grown = realloc(array, new_size * sizeof(cell_t)); if (!grown) {OUT_OF_MEMORY} array = grown; memset(array + size, 0, (new_size - size) * sizeof(cell_t)); size = new_size;",
+    }
+    .replace("{MESSAGE}", tape.mode.off_tape_message())
+    .replace("{OUT_OF_BOUNDS}", &format!("{{{}}}", out_of_bounds))
+    .replace("{OUT_OF_MEMORY}", &format!("{{{}}}", out_of_memory));
+
+    // only a growing tape needs to be on the heap, a fixed one lets the
+    // compiler know stores to cells never change array or size
+    let tape_decl = match tape.mode {
+        TapeMode::Grow => {
+            format!("static cell_t *array; static long size = {};", tape.size)
+        }
+        _ => format!(
+            "static cell_t array[{0}]; static const long size = {0};",
+            tape.size
+        ),
+    };
+    let tape_init = match tape.mode {
+        TapeMode::Grow => format!(
+            "array = calloc(size, sizeof(cell_t)); if (!array) {{{}}}",
+            out_of_memory
+        ),
+        _ => "".into(),
+    };
+
+    let cell_type = match tape.cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    };
+
+    let mut result = format!("#define _GNU_SOURCE\n#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\ntypedef {} cell_t;\n{}\nstatic cell_t *cell(long index, int line, int col){{if ((unsigned long)index >= (unsigned long)size) {{{}}} return &array[index];}}\nint main(){{{}long pos = 0;", cell_type, tape_decl, off_tape, tape_init);
+
+    c_translate_block(block, tape, eof, &mut result);
+
+    Ok(result + "return 0;}")
+}
+
+// Prints an error and exits, {MESSAGE} is replaced with its message, C_FAIL_AT
+// adds the position in line and col
+const C_FAIL_AT: &str = "fflush(stdout); fprintf(stderr, \"error: {MESSAGE} %d:%d\\n\", line, col); exit(1);";
+
+const C_FAIL: &str =
+    "fflush(stdout); fputs(\"error: {MESSAGE}\\n\", stderr); exit(1);";
+
+// The same line as debug_dump, {RADIUS} is replaced with the radius
+const C_DEBUG_DUMP: &str = "fflush(stdout); {long first = pos - {RADIUS} < 0 ? 0 : pos - {RADIUS}; long last = pos + {RADIUS} >= size ? size - 1 : pos + {RADIUS}; fprintf(stderr, \"# pointer %ld, cells\", pos); if (first > last) fputs(\" none\", stderr); else {fprintf(stderr, \" %ld-%ld:\", first, last); for (long i = first; i <= last; i++) fprintf(stderr, i == pos ? \" [%lu]\" : \" %lu\", (unsigned long)array[i]);} fputc('\\n', stderr);}";
+
+fn c_move(n: isize, code_pos: &CodePos, tape: &TapeConfig) -> String {
+    match tape.mode {
+        TapeMode::Wrap => format!(
+            "pos = cell(pos + {}, {}, {}) - array;",
+            n, code_pos.line, code_pos.col
+        ),
+        TapeMode::Strict => format!(
+            "pos += {}; cell(pos, {}, {});",
+            n, code_pos.line, code_pos.col
+        ),
+        _ => format!("pos += {};", n),
+    }
+}
+
+// ptr[offset] += *ptr * factor, cell gives the C expression for a cell
+fn c_mul_add(
+    offset: isize,
+    factor: isize,
+    cell: &impl Fn(isize) -> String,
+    tape: &TapeConfig,
+) -> String {
+    match tape.mode {
+        TapeMode::Wrap => {
+            format!("{} += {} * {};", cell(offset), cell(0), factor)
+        }
+        _ => format!(
+            "{{cell_t value = {}; if (value) {} += value * {};}}",
+            cell(0),
+            cell(offset),
+            factor
+        ),
+    }
+}
+
+fn c_translate_block(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+    result: &mut String,
+) {
+    for node in block {
+        let token = match node {
+            Node::Loop(inner) => {
+                *result += &format!(
+                    "while (*cell(pos, {}, {})) {{",
+                    inner.code_pos.line, inner.code_pos.col
+                );
+                c_translate_block(&inner.body, tape, eof, result);
+                *result += "}";
+                continue;
+            }
+            Node::Token(token) => token,
+        };
+
+        let cell = |offset: isize| {
+            format!(
+                "*cell(pos + {}, {}, {})",
+                offset, token.code_pos.line, token.code_pos.col
+            )
+        };
+
+        *result += &match token.kind {
+            TokenKind::Output { offset } => {
+                format!("putchar({});", cell(offset))
+            }
+            TokenKind::Input { offset } => match eof.value() {
+                Some(value) => format!(
+                    "{{int value = getchar(); {} = value == EOF ? {}u : value;}}",
+                    cell(offset),
+                    value
+                ),
+                None => format!(
+                    "{{int value = getchar(); if (value != EOF) {} = value;}}",
+                    cell(offset)
+                ),
+            },
+            TokenKind::Clear { offset } => format!("{} = 0;", cell(offset)),
+            TokenKind::ValMod { offset, delta } => {
+                format!("{} += {};", cell(offset), delta)
             }
-            TokenKind::Bracket(BracketState::Open) => "while (*ptr) {".into(),
-            TokenKind::Bracket(BracketState::Closed) => "}".into(),
-            TokenKind::Copy(value) => format!(
-                "{{char *tptr = array;tptr = ptr;tptr+={};*tptr += *ptr;}}", // TODO: this can probably be improved
-                value
+            TokenKind::PosMod(value) => c_move(value, &token.code_pos, tape),
+            TokenKind::Copy(offset) => c_mul_add(offset, 1, &cell, tape),
+            TokenKind::MulAdd { offset, factor } => {
+                c_mul_add(offset, factor, &cell, tape)
+            }
+            // search the rest of the tape in one go, then continue past its
+            // end cell by cell, memchr only works for byte cells
+            TokenKind::Scan(1) if tape.cell_width == CellWidth::U8 => format!(
+                "{{cell_t *start = &{}; cell_t *found = memchr(start, 0, size - pos); if (found) pos = found - array; else {{pos = size; while ({}) {{{}}}}}}}",
+                cell(0),
+                cell(0),
+                c_move(1, &token.code_pos, tape)
+            ),
+            TokenKind::Scan(-1) if tape.cell_width == CellWidth::U8 => format!(
+                "{{cell_t *start = &{}; cell_t *found = memrchr(array, 0, start - array + 1); if (found) pos = found - array; else {{pos = -1; while ({}) {{{}}}}}}}",
+                cell(0),
+                cell(0),
+                c_move(-1, &token.code_pos, tape)
+            ),
+            TokenKind::Scan(stride) => format!(
+                "while ({}) {{{}}}",
+                cell(0),
+                c_move(stride, &token.code_pos, tape)
             ),
-            TokenKind::Comment => "".into(),
+            TokenKind::Debug { radius } => {
+                C_DEBUG_DUMP.replace("{RADIUS}", &radius.to_string())
+            }
+            TokenKind::Bracket(_) | TokenKind::Comment => "".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: usize, col: usize, offset: usize) -> CodePos {
+        CodePos { line, col, offset }
+    }
+
+    fn positions(input: &str) -> Vec<CodePos> {
+        tokenize(input, Dialect::default())
+            .into_iter()
+            .map(|token| token.code_pos)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_counts_comments() {
+        assert_eq!(
+            positions("add + then move >\n  -"),
+            [pos(1, 5, 4), pos(1, 17, 16), pos(2, 3, 20)]
+        );
+    }
+
+    #[test]
+    fn tokenize_line_endings() {
+        assert_eq!(positions("+\n\n-"), [pos(1, 1, 0), pos(3, 1, 3)]);
+        // \r is a comment taking up a column at the end of its line
+        assert_eq!(positions("+\r\n-\r\n"), [pos(1, 1, 0), pos(2, 1, 3)]);
+    }
+
+    #[test]
+    fn tokenize_multibyte_comments() {
+        // col counts characters, offset counts bytes
+        assert_eq!(positions("ü€>"), [pos(1, 3, 5)]);
+        assert_eq!(positions("é\n→ ."), [pos(2, 3, 7)]);
+    }
+
+    fn unmatched(input: &str) -> Vec<(BracketState, CodePos)> {
+        let errors =
+            validate(&tokenize(input, Dialect::default())).unwrap_err();
+        errors
+            .into_iter()
+            .map(|error| match error {
+                Error::UnbalancedBracket(state, code_pos) => (state, code_pos),
+                error => panic!("not a bracket error: {}", error),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validate_balanced() {
+        assert!(validate(&tokenize("+[>[-]<]", Dialect::default())).is_ok());
+    }
+
+    #[test]
+    fn validate_reports_in_source_order() {
+        use BracketState::{Closed, Open};
+        // unmatched closing brackets are found first, but reported in order
+        // with the opening ones
+        assert_eq!(
+            unmatched("[ ]]\n[[-]"),
+            [(Closed, pos(1, 4, 3)), (Open, pos(2, 1, 5))]
+        );
+        assert_eq!(
+            unmatched("[x]]\n[ \n]]]"),
+            [
+                (Closed, pos(1, 4, 3)),
+                (Closed, pos(3, 2, 9)),
+                (Closed, pos(3, 3, 10))
+            ]
+        );
+        assert_eq!(
+            unmatched("[ [\n] ]] [ ["),
+            [
+                (Closed, pos(2, 4, 7)),
+                (Open, pos(2, 6, 9)),
+                (Open, pos(2, 8, 11))
+            ]
+        );
+    }
+
+    #[test]
+    fn render_points_at_the_column() {
+        let error = Error::UnbalancedBracket(BracketState::Open, pos(2, 3, 4));
+        assert_eq!(
+            error.render("prog.bf", "+\n  [-\n"),
+            concat!(
+                "error: unmatched '[' at 2:3\n",
+                " --> prog.bf:2:3\n",
+                "  |\n",
+                "2 |   [-\n",
+                "  |   ^\n",
+            )
+        );
+        // tabs are kept so the caret lines up
+        let error = Error::OutOfBounds(pos(1, 3, 2));
+        assert_eq!(
+            error.render("tab.bf", "\t<."),
+            concat!(
+                "error: cell outside of the tape accessed at 1:3\n",
+                " --> tab.bf:1:3\n",
+                "  |\n",
+                "1 | \t<.\n",
+                "  | \t ^\n",
+            )
+        );
+        // errors without a position are rendered on their own
+        assert_eq!(
+            Error::EmptyTape.render("prog.bf", "+"),
+            "error: the tape needs at least one cell\n"
+        );
+    }
+
+    // Runs optimize_loop on the only loop in input like optimize does
+    fn replaced_in(
+        input: &str,
+        mode: TapeMode,
+    ) -> Result<Vec<TokenKind>, &'static str> {
+        let mut inner = match parse(input, Dialect::default()).unwrap().pop() {
+            Some(Node::Loop(inner)) => inner,
+            node => panic!("not a loop: {:?}", node),
+        };
+        inner.body = optimize(inner.body, mode);
+        let tokens = optimize_loop(&inner, mode)?;
+        Ok(tokens.into_iter().map(|token| token.kind).collect())
+    }
+
+    fn replaced(input: &str) -> Result<Vec<TokenKind>, &'static str> {
+        replaced_in(input, TapeMode::Wrap)
+    }
+
+    #[test]
+    fn optimize_loop_replaces_mul_add_loops() {
+        assert_eq!(
+            replaced("[->+++>--<<]"),
+            Ok(vec![
+                TokenKind::MulAdd {
+                    offset: 1,
+                    factor: 3
+                },
+                TokenKind::MulAdd {
+                    offset: 2,
+                    factor: -2
+                },
+                TokenKind::Clear { offset: 0 },
+            ])
+        );
+        assert_eq!(
+            replaced("[>++<-]"),
+            Ok(vec![
+                TokenKind::MulAdd {
+                    offset: 1,
+                    factor: 2
+                },
+                TokenKind::Clear { offset: 0 },
+            ])
+        );
+        assert_eq!(
+            replaced("[>+<-]"),
+            Ok(vec![TokenKind::Copy(1), TokenKind::Clear { offset: 0 }])
+        );
+        assert_eq!(replaced("[-]"), Ok(vec![TokenKind::Clear { offset: 0 }]));
+        assert_eq!(replaced("[<<]"), Ok(vec![TokenKind::Scan(-2)]));
+    }
+
+    #[test]
+    fn optimize_loop_keeps_other_loops() {
+        let decrement = Err("doesn't decrement the loop cell by one");
+        assert_eq!(replaced("[-->+<]"), decrement);
+        assert_eq!(replaced("[>+<]"), decrement);
+        assert_eq!(replaced("[]"), decrement);
+        assert_eq!(replaced("[>+<->]"), Err("moves the pointer"));
+        assert_eq!(replaced("[-.]"), Err("does I/O"));
+        assert_eq!(replaced("[-[-]>]"), Err("contains another loop"));
+    }
+
+    #[test]
+    fn optimize_loop_keeps_unchanged_cells_checked() {
+        // the cell left of the loop cell is touched but ends up unchanged
+        assert_eq!(
+            replaced("[-<+->]"),
+            Ok(vec![TokenKind::Clear { offset: 0 }])
+        );
+        for mode in [TapeMode::Error, TapeMode::Grow] {
+            assert_eq!(
+                replaced_in("[-<+->]", mode),
+                Err("touches a cell without changing it")
+            );
+            assert!(replaced_in("[-<+>]", mode).is_ok());
         }
     }
 
-    result + "return 0;}"
+    #[test]
+    fn optimize_loop_keeps_strict_moves() {
+        let strict = |input| replaced_in(input, TapeMode::Strict);
+        assert_eq!(strict("[->+<]"), Err("moves the pointer in strict mode"));
+        assert_eq!(strict("[-]"), Ok(vec![TokenKind::Clear { offset: 0 }]));
+        assert_eq!(strict("[>>]"), Ok(vec![TokenKind::Scan(2)]));
+    }
+
+    #[test]
+    fn optimize_keeps_strict_moves_apart() {
+        let kinds = |mode| -> Vec<TokenKind> {
+            let program = parse(">>+<<<>", Dialect::default()).unwrap();
+            optimize(program, mode)
+                .into_iter()
+                .map(|node| match node {
+                    Node::Token(token) => token.kind,
+                    node => panic!("not a token: {:?}", node),
+                })
+                .collect()
+        };
+        assert_eq!(
+            kinds(TapeMode::Wrap),
+            [TokenKind::ValMod {
+                offset: 2,
+                delta: 1
+            }]
+        );
+        assert_eq!(
+            kinds(TapeMode::Strict),
+            [
+                TokenKind::PosMod(2),
+                TokenKind::ValMod {
+                    offset: 0,
+                    delta: 1
+                },
+                TokenKind::PosMod(-3),
+                TokenKind::PosMod(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn optimize_keeps_merged_span() {
+        let program = parse("+ +\n+-+", Dialect::default()).unwrap();
+        let program = optimize(program, TapeMode::Wrap);
+        let token = match program.as_slice() {
+            [Node::Token(token)] => token,
+            _ => panic!("not merged into one token: {:?}", program),
+        };
+        assert_eq!(
+            token.kind,
+            TokenKind::ValMod {
+                offset: 0,
+                delta: 3
+            }
+        );
+        assert_eq!(token.code_pos, pos(1, 1, 0));
+        assert_eq!(token.end_pos, pos(2, 3, 6));
+        assert_eq!(token.span(), 0..7);
+    }
+
+    #[test]
+    fn tape_config_checks_size() {
+        let new = |size, cell_width| {
+            TapeConfig::new(size, TapeMode::Wrap, cell_width)
+        };
+        assert!(matches!(new(0, CellWidth::U8), Err(Error::EmptyTape)));
+        let size = isize::MAX as usize;
+        assert!(new(size, CellWidth::U8).is_ok());
+        assert!(matches!(
+            new(size, CellWidth::U16),
+            Err(Error::TapeTooLarge)
+        ));
+        assert!(matches!(
+            new(usize::MAX, CellWidth::U32),
+            Err(Error::TapeTooLarge)
+        ));
+
+        // wasm only has 32 bit addresses
+        let tape = new(1 << 30, CellWidth::U16).unwrap();
+        let module = wasm_translate(&vec![], &tape, EofBehavior::Zero, false);
+        assert!(matches!(module, Err(Error::TapeTooLarge)));
+    }
+
+    #[test]
+    fn tokenize_debug_dump() {
+        let kinds = |dialect| {
+            let tokens = tokenize("#", dialect);
+            tokens
+                .into_iter()
+                .map(|token| token.kind)
+                .collect::<Vec<_>>()
+        };
+        // `#` is a comment by default
+        assert_eq!(kinds(Dialect::default()), []);
+        let dialect = Dialect {
+            debug_dump: Some(2),
+        };
+        assert_eq!(kinds(dialect), [TokenKind::Debug { radius: 2 }]);
+    }
+
+    #[test]
+    fn debug_dump_format() {
+        let cell = |index: usize| index as u32 * 10;
+        assert_eq!(
+            debug_dump(3, 2, 8, cell),
+            "# pointer 3, cells 1-5: 10 20 [30] 40 50\n"
+        );
+        // cut off at both ends of the tape
+        assert_eq!(
+            debug_dump(1, 2, 3, cell),
+            "# pointer 1, cells 0-2: 0 [10] 20\n"
+        );
+        assert_eq!(debug_dump(-5, 2, 8, cell), "# pointer -5, cells none\n");
+        assert_eq!(debug_dump(10, 2, 8, cell), "# pointer 10, cells none\n");
+        assert_eq!(
+            debug_dump(2, usize::MAX, 4, cell),
+            "# pointer 2, cells 0-3: 0 10 [20] 30\n"
+        );
+    }
+
+    #[test]
+    fn c_translate_eof() {
+        let program = parse(",", Dialect::default()).unwrap();
+        let cases = [
+            (
+                EofBehavior::Unchanged,
+                "if (value != EOF) *cell(pos + 0, 1, 1) = value;",
+            ),
+            (
+                EofBehavior::Zero,
+                "*cell(pos + 0, 1, 1) = value == EOF ? 0u : value;",
+            ),
+            (
+                EofBehavior::MinusOne,
+                "*cell(pos + 0, 1, 1) = value == EOF ? 4294967295u : value;",
+            ),
+        ];
+        for (eof, expected) in cases {
+            let code = c_translate(&program, &TapeConfig::default(), eof);
+            assert!(code.unwrap().contains(expected), "{:?}", eof);
+        }
+    }
 }
@@ -0,0 +1,260 @@
+use crate::{
+    emit::Emitter, ir::Block, CellWidth, CodePos, EofBehavior, Error,
+    TapeConfig, TapeMode, OUT_OF_MEMORY_MESSAGE,
+};
+
+/*
+Translates to textual LLVM IR, it needs LLVM 15 or newer for opaque pointers.
+pos lives in an alloca that mem2reg turns into a register, and every cell
+access goes through @index which handles the tape mode like cell() does in
+the C output, opt inlines it.
+*/
+
+// Translates a program tree to LLVM IR
+pub fn llvm_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+) -> Result<String, Error> {
+    tape.validate()?;
+    let cell_type = match tape.cell_width {
+        CellWidth::U8 => "i8",
+        CellWidth::U16 => "i16",
+        CellWidth::U32 => "i32",
+    };
+
+    // only a growing tape needs to be on the heap, a fixed one lets llvm
+    // know the size never changes
+    let (tape_decl, size, array) = match tape.mode {
+        TapeMode::Grow => (
+            format!(
+                "@array = internal global ptr null\n@size = internal global i64 {}\n@oom_message = private constant [{} x i8] c\"error: {}\\0A\\00\"\n",
+                tape.size,
+                OUT_OF_MEMORY_MESSAGE.len() + 9,
+                OUT_OF_MEMORY_MESSAGE
+            ),
+            "%size = load i64, ptr @size\n",
+            "%array = load ptr, ptr @array\n",
+        ),
+        _ => (
+            format!(
+                "@array = internal global [{} x {}] zeroinitializer\n",
+                tape.size, cell_type
+            ),
+            "",
+            "",
+        ),
+    };
+    let size_value = match tape.mode {
+        TapeMode::Grow => "%size".to_string(),
+        _ => tape.size.to_string(),
+    };
+    let array_value = match tape.mode {
+        TapeMode::Grow => "%array",
+        _ => "@array",
+    };
+
+    let off_tape = match tape.mode {
+        TapeMode::Wrap => format!(
+            "%rem = srem i64 %index, {0}\n%negative = icmp slt i64 %rem, 0\n%wrapped = add i64 %rem, {0}\n%fixed = select i1 %negative, i64 %wrapped, i64 %rem\nret i64 %fixed\n",
+            size_value
+        ),
+        TapeMode::Error | TapeMode::Strict => LLVM_OUT_OF_BOUNDS.to_string(),
+        TapeMode::Grow => format!(
+            "%left = icmp slt i64 %index, 0\nbr i1 %left, label %error, label %grow\nerror:\n{}grow:\n%doubled = mul i64 %size, 2\n%needed = add i64 %index, 1\n%enough = icmp sgt i64 %doubled, %index\n%new_size = select i1 %enough, i64 %doubled, i64 %needed\n%new_bytes = mul i64 %new_size, {1}\n%new_array = call ptr @realloc(ptr %array, i64 %new_bytes)\n%failed = icmp eq ptr %new_array, null\nbr i1 %failed, label %out_of_memory, label %grown\nout_of_memory:\n{2}grown:\n%old_bytes = mul i64 %size, {1}\n%new_cells = getelementptr i8, ptr %new_array, i64 %old_bytes\n%added_bytes = sub i64 %new_bytes, %old_bytes\ncall void @llvm.memset.p0.i64(ptr %new_cells, i8 0, i64 %added_bytes, i1 false)\nstore ptr %new_array, ptr @array\nstore i64 %new_size, ptr @size\nret i64 %index\n",
+            LLVM_OUT_OF_BOUNDS,
+            tape.cell_width.bytes(),
+            LLVM_OUT_OF_MEMORY
+        ),
+    };
+    let tape_init = match tape.mode {
+        TapeMode::Grow => format!(
+            "%tape = call ptr @calloc(i64 {}, i64 {})\n%no_tape = icmp eq ptr %tape, null\nbr i1 %no_tape, label %out_of_memory, label %start\nout_of_memory:\n{}start:\nstore ptr %tape, ptr @array\n",
+            tape.size,
+            tape.cell_width.bytes(),
+            LLVM_OUT_OF_MEMORY
+        ),
+        _ => "".into(),
+    };
+
+    // printf format of the error, followed by a newline
+    let message = format!("error: {} %d:%d", tape.mode.off_tape_message());
+    let mut result = format!("{}@message = private constant [{} x i8] c\"{}\\0A\\00\"\n\ndeclare i32 @getchar()\ndeclare i32 @putchar(i32)\ndeclare i32 @fflush(ptr)\ndeclare i32 @dprintf(i32, ptr, ...)\ndeclare void @exit(i32)\ndeclare ptr @calloc(i64, i64)\ndeclare ptr @realloc(ptr, i64)\ndeclare void @llvm.memset.p0.i64(ptr, i8, i64, i1)\n\n; index of a cell that is on the tape\ndefine internal i64 @index(i64 %index, i32 %line, i32 %col) alwaysinline {{\n{}{}%outside = icmp uge i64 %index, {}\nbr i1 %outside, label %off_tape, label %on_tape\non_tape:\nret i64 %index\noff_tape:\n{}}}\n\n; address of a cell\ndefine internal ptr @cell(i64 %index, i32 %line, i32 %col) alwaysinline {{\n%fixed = call i64 @index(i64 %index, i32 %line, i32 %col)\n{}%address = getelementptr {}, ptr {}, i64 %fixed\nret ptr %address\n}}\n\ndefine i32 @main() {{\n%pos = alloca i64\nstore i64 0, ptr %pos\n{}", tape_decl, message.len() + 2, message, size, array, size_value, off_tape, array, cell_type, array_value, tape_init);
+
+    let mut translator = Translator {
+        result: &mut result,
+        next_id: 0,
+        cell_type,
+        tape,
+        eof,
+    };
+    translator.block(block);
+
+    Ok(result + "ret i32 0\n}\n")
+}
+
+const LLVM_OUT_OF_BOUNDS: &str = "call i32 @fflush(ptr null)\ncall i32 (i32, ptr, ...) @dprintf(i32 2, ptr @message, i32 %line, i32 %col)\ncall void @exit(i32 1)\nunreachable\n";
+
+const LLVM_OUT_OF_MEMORY: &str = "call i32 @fflush(ptr null)\ncall i32 (i32, ptr, ...) @dprintf(i32 2, ptr @oom_message)\ncall void @exit(i32 1)\nunreachable\n";
+
+struct Translator<'a> {
+    result: &'a mut String,
+    // makes names of values and labels unique
+    next_id: usize,
+    cell_type: &'static str,
+    tape: &'a TapeConfig,
+    eof: EofBehavior,
+}
+
+impl Translator<'_> {
+    fn id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Value truncated to the cell width, llvm wants constants that fit
+    fn constant(&self, value: isize) -> i64 {
+        match self.tape.cell_width {
+            CellWidth::U8 => value as i8 as i64,
+            CellWidth::U16 => value as i16 as i64,
+            CellWidth::U32 => value as i32 as i64,
+        }
+    }
+
+    // Returns the name of a pointer to the cell at pos + offset
+    fn cell(&mut self, offset: isize, code_pos: &CodePos) -> String {
+        let id = self.id();
+        *self.result += &format!(
+            "%pos{0} = load i64, ptr %pos\n%index{0} = add i64 %pos{0}, {1}\n%cell{0} = call ptr @cell(i64 %index{0}, i32 {2}, i32 {3})\n",
+            id, offset, code_pos.line, code_pos.col
+        );
+        format!("%cell{}", id)
+    }
+
+    // Returns the name of the value of the cell at pos + offset
+    fn load(&mut self, offset: isize, code_pos: &CodePos) -> String {
+        let cell = self.cell(offset, code_pos);
+        let id = self.id();
+        *self.result +=
+            &format!("%value{} = load {}, ptr {}\n", id, self.cell_type, cell);
+        format!("%value{}", id)
+    }
+}
+
+impl Emitter for Translator<'_> {
+    fn while_loop(&mut self, code_pos: &CodePos, body: impl FnOnce(&mut Self)) {
+        let id = self.id();
+        *self.result += &format!("br label %loop{0}\nloop{0}:\n", id);
+        let value = self.load(0, code_pos);
+        *self.result += &format!(
+            "%nonzero{0} = icmp ne {1} {2}, 0\nbr i1 %nonzero{0}, label %body{0}, label %end{0}\nbody{0}:\n",
+            id, self.cell_type, value
+        );
+        body(self);
+        *self.result += &format!("br label %loop{0}\nend{0}:\n", id);
+    }
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos) {
+        let id = self.id();
+        *self.result += &format!(
+            "%pos{0} = load i64, ptr %pos\n%moved{0} = add i64 %pos{0}, {1}\n",
+            id, n
+        );
+        match self.tape.mode {
+            TapeMode::Wrap | TapeMode::Strict => {
+                *self.result += &format!(
+                    "%wrapped{0} = call i64 @index(i64 %moved{0}, i32 {1}, i32 {2})\nstore i64 %wrapped{0}, ptr %pos\n",
+                    id, code_pos.line, code_pos.col
+                );
+            }
+            _ => *self.result += &format!("store i64 %moved{}, ptr %pos\n", id),
+        }
+    }
+
+    fn add(&mut self, offset: isize, delta: isize, code_pos: &CodePos) {
+        let cell = self.cell(offset, code_pos);
+        let id = self.id();
+        *self.result += &format!(
+            "%old{0} = load {1}, ptr {2}\n%new{0} = add {1} %old{0}, {3}\nstore {1} %new{0}, ptr {2}\n",
+            id,
+            self.cell_type,
+            cell,
+            self.constant(delta)
+        );
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize, code_pos: &CodePos) {
+        let value = self.load(0, code_pos);
+        let id = self.id();
+        let guarded = self.tape.mode != TapeMode::Wrap;
+        if guarded {
+            *self.result += &format!(
+                "%nonzero{0} = icmp ne {1} {2}, 0\nbr i1 %nonzero{0}, label %add{0}, label %skip{0}\nadd{0}:\n",
+                id, self.cell_type, value
+            );
+        }
+        let target = self.cell(offset, code_pos);
+        *self.result += &format!(
+            "%old{0} = load {1}, ptr {2}\n%product{0} = mul {1} {3}, {4}\n%sum{0} = add {1} %old{0}, %product{0}\nstore {1} %sum{0}, ptr {2}\n",
+            id,
+            self.cell_type,
+            target,
+            value,
+            self.constant(factor)
+        );
+        if guarded {
+            *self.result += &format!("br label %skip{0}\nskip{0}:\n", id);
+        }
+    }
+
+    fn clear(&mut self, offset: isize, code_pos: &CodePos) {
+        let cell = self.cell(offset, code_pos);
+        *self.result += &format!("store {} 0, ptr {}\n", self.cell_type, cell);
+    }
+
+    fn output(&mut self, offset: isize, code_pos: &CodePos) {
+        let value = self.load(offset, code_pos);
+        let id = self.id();
+        *self.result += &match self.tape.cell_width {
+            CellWidth::U32 => format!("call i32 @putchar(i32 {})\n", value),
+            _ => format!(
+                "%char{0} = zext {1} {2} to i32\ncall i32 @putchar(i32 %char{0})\n",
+                id, self.cell_type, value
+            ),
+        };
+    }
+
+    fn input(&mut self, offset: isize, code_pos: &CodePos) {
+        let cell = self.cell(offset, code_pos);
+        let id = self.id();
+        // make sure prompts are visible before blocking on input
+        *self.result += &format!(
+            "call i32 @fflush(ptr null)\n%char{0} = call i32 @getchar()\n%eof{0} = icmp eq i32 %char{0}, -1\n",
+            id
+        );
+        let read = match self.tape.cell_width {
+            CellWidth::U32 => format!("%char{}", id),
+            _ => {
+                *self.result += &format!(
+                    "%read{0} = trunc i32 %char{0} to {1}\n",
+                    id, self.cell_type
+                );
+                format!("%read{}", id)
+            }
+        };
+        let eof_value = match self.eof.value() {
+            Some(value) => self.constant(value as isize).to_string(),
+            None => {
+                *self.result += &format!(
+                    "%old{0} = load {1}, ptr {2}\n",
+                    id, self.cell_type, cell
+                );
+                format!("%old{}", id)
+            }
+        };
+        *self.result += &format!(
+            "%new{0} = select i1 %eof{0}, {1} {2}, {1} {3}\nstore {1} %new{0}, ptr {4}\n",
+            id, self.cell_type, eof_value, read, cell
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use crate::{
+    interpreter::Machine,
+    ir::{Block, Loop, Node},
+    optimize_loop, BracketState, CodePos, EofBehavior, Error, TapeConfig,
+    Token, TokenKind,
+};
+
+/*
+Counts how often every token of a program runs on the interpreter. Loops are
+flattened into brackets by the interpreter: the opening bracket runs once
+every time the loop is entered and the closing one once per iteration. Loops
+still in the program after optimize are the ones it couldn't replace with
+Clear, Copy or MulAdd tokens, the report says why for each of them.
+*/
+
+// Runs a program counting how often every token runs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Profiler {
+    pub eof: EofBehavior,
+    pub tape: TapeConfig,
+}
+
+impl Profiler {
+    pub fn run(
+        &self,
+        program: &Block,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<Profile, Error> {
+        let mut machine = Machine::new(program, self.eof, self.tape)?;
+        let mut counts = vec![0u64; machine.tokens.len()];
+        while !machine.is_done() {
+            counts[machine.pos] += 1;
+            machine.step(input, output)?;
+        }
+        output.flush()?;
+
+        let mut lines = BTreeMap::new();
+        for (token, count) in machine.tokens.iter().zip(&counts) {
+            *lines.entry(token.code_pos.line).or_insert(0) += count;
+        }
+
+        // the interpreter flattens loops in the same order as collect_loops
+        let mut inner_loops = vec![];
+        collect_loops(program, &mut inner_loops);
+        let brackets = machine.tokens.iter().zip(&counts).enumerate().filter(
+            |(_, (token, _))| {
+                token.kind == TokenKind::Bracket(BracketState::Open)
+            },
+        );
+        let loops = inner_loops
+            .into_iter()
+            .zip(brackets)
+            .map(|(inner, (open, (_, entries)))| LoopProfile {
+                code_pos: inner.code_pos.clone(),
+                entries: *entries,
+                iterations: counts[machine.jumps[open]],
+                reason: optimize_loop(inner, self.tape.mode)
+                    .err()
+                    .unwrap_or("unknown"),
+            })
+            .collect();
+
+        Ok(Profile {
+            total: counts.iter().sum(),
+            lines: lines.into_iter().collect(),
+            loops,
+            tokens: machine.tokens.into_iter().zip(counts).collect(),
+        })
+    }
+}
+
+fn collect_loops<'a>(block: &'a Block, loops: &mut Vec<&'a Loop>) {
+    for node in block {
+        if let Node::Loop(inner) = node {
+            loops.push(inner);
+            collect_loops(&inner.body, loops);
+        }
+    }
+}
+
+// What a profiled run did
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // Tokens run in total, brackets included
+    pub total: u64,
+    // Tokens run per source line, tokens merged by optimize count for the
+    // line they start on
+    pub lines: Vec<(usize, u64)>,
+    // Every loop left in the program in source order
+    pub loops: Vec<LoopProfile>,
+    // Times every token ran, in the order the interpreter runs them with
+    // loops flattened into brackets
+    pub tokens: Vec<(Token, u64)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopProfile {
+    // Position of the opening bracket
+    pub code_pos: CodePos,
+    // Times the loop was reached
+    pub entries: u64,
+    // Times the body ran
+    pub iterations: u64,
+    // Why optimize kept the loop
+    pub reason: &'static str,
+}
+
+impl Profile {
+    // Lists the top lines that ran the most tokens and the top loops that
+    // ran the most iterations, source is the text the program was parsed from
+    pub fn report(&self, source: &str, top: usize) -> String {
+        let mut report = format!("profile: {} tokens run\n", self.total);
+
+        let mut lines = self.lines.clone();
+        lines.retain(|(_, count)| *count > 0);
+        lines.sort_by_key(|&(line, count)| (Reverse(count), line));
+        report += "hottest lines:\n";
+        for (line, count) in lines.iter().take(top) {
+            let text = source.lines().nth(line - 1).unwrap_or("").trim();
+            let text: String = text.chars().take(40).collect();
+            report += &format!(
+                "{:>8} {:>14} {:>5.1}%  {}\n",
+                line,
+                count,
+                *count as f64 * 100.0 / self.total as f64,
+                text
+            );
+        }
+        report += &more(lines.len(), top);
+
+        let mut loops: Vec<&LoopProfile> =
+            self.loops.iter().filter(|l| l.entries > 0).collect();
+        loops.sort_by_key(|l| Reverse(l.iterations));
+        report += "loops optimize kept, by iterations:\n";
+        if loops.is_empty() {
+            report += "  none ran\n";
+        }
+        for profile in loops.iter().take(top) {
+            report += &format!(
+                "{:>8} {:>14} iterations, entered {} times, {}\n",
+                profile.code_pos.to_string(),
+                profile.iterations,
+                profile.entries,
+                profile.reason
+            );
+        }
+        report + &more(loops.len(), top)
+    }
+}
+
+// Notes how many entries past top a list left out
+fn more(len: usize, top: usize) -> String {
+    match len.checked_sub(top) {
+        Some(left) if left > 0 => format!("  and {} more\n", left),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse, Dialect, TapeMode};
+
+    fn program(source: &str) -> Block {
+        optimize(parse(source, Dialect::default()).unwrap(), TapeMode::Wrap)
+    }
+
+    // Why optimize_loop keeps the first loop of source
+    fn reason(source: &str) -> &'static str {
+        match &program(source)[..] {
+            [Node::Loop(inner), ..] => {
+                optimize_loop(inner, TapeMode::Wrap).unwrap_err()
+            }
+            _ => panic!("{} doesn't start with a loop", source),
+        }
+    }
+
+    #[test]
+    fn profile_counts_kept_loops() {
+        use BracketState::{Closed, Open};
+        // a copy loop, an I/O loop, a scan loop and nested loops
+        let source = "+++++[->+<]\n>[-.]\n>+>+<<+[>]\n++[>+++[-.]<-]\n";
+        let mut output = vec![];
+        let profile = Profiler::default()
+            .run(&program(source), &mut &b""[..], &mut output)
+            .unwrap();
+        assert_eq!(output, [4, 3, 2, 1, 0, 2, 1, 0, 2, 1, 0]);
+
+        // the copy and the scan loop were replaced and aren't profiled
+        let loops: Vec<(String, u64, u64, &str)> = profile
+            .loops
+            .iter()
+            .map(|l| {
+                (l.code_pos.to_string(), l.entries, l.iterations, l.reason)
+            })
+            .collect();
+        assert_eq!(
+            loops,
+            [
+                ("2:2".to_string(), 1, 5, reason("[-.]")),
+                ("4:3".to_string(), 1, 2, reason("[>+++[-.]<-]")),
+                ("4:8".to_string(), 2, 6, reason("[-.]")),
+            ]
+        );
+        assert_eq!(loops[0].3, "does I/O");
+        assert_eq!(loops[1].3, "contains another loop");
+
+        // the I/O loop on line 2, its body ran once per iteration
+        let line_2: Vec<(String, &TokenKind, u64)> = profile
+            .tokens
+            .iter()
+            .filter(|(token, _)| token.code_pos.line == 2)
+            .map(|(token, count)| {
+                (token.code_pos.to_string(), &token.kind, *count)
+            })
+            .collect();
+        assert_eq!(
+            line_2,
+            [
+                ("2:1".to_string(), &TokenKind::PosMod(1), 1),
+                ("2:2".to_string(), &TokenKind::Bracket(Open), 1),
+                (
+                    "2:3".to_string(),
+                    &TokenKind::ValMod {
+                        offset: 0,
+                        delta: -1
+                    },
+                    5
+                ),
+                ("2:4".to_string(), &TokenKind::Output { offset: 0 }, 5),
+                ("2:5".to_string(), &TokenKind::Bracket(Closed), 5),
+            ]
+        );
+        let total: u64 = profile.tokens.iter().map(|(_, count)| count).sum();
+        assert_eq!(total, profile.total);
+
+        let report = profile.report(source, 10);
+        assert!(report.contains("6 iterations, entered 2 times, does I/O"));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{
+    emit::Emitter, ir::Block, CellWidth, CodePos, EofBehavior, Error,
+    TapeConfig, TapeMode,
+};
+
+/*
+Translates to a python 3 script. The program is `run(stdin, stdout)` so it
+can be imported and given other binary streams, sys.stdin.buffer and
+sys.stdout.buffer are used by default. 8 bit cells live in a bytearray,
+wider ones in a list, every store is masked to the cell width.
+
+CPython refuses more than 20 statically nested blocks in a function, so
+loops nested deeper than that are moved into local functions.
+*/
+
+// Loops nested in one python function before the next one starts a new one
+const MAX_NESTED_LOOPS: usize = 16;
+
+// Translates a program tree to python
+pub fn python_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+) -> Result<String, Error> {
+    tape.validate()?;
+    let tape_init = match tape.cell_width {
+        CellWidth::U8 => "bytearray(SIZE)",
+        _ => "[0] * SIZE",
+    };
+
+    let cell_fn = match tape.mode {
+        TapeMode::Wrap => {
+            "def cell(index, line, col):
+        return index % SIZE"
+        }
+        TapeMode::Error | TapeMode::Strict => {
+            "def cell(index, line, col):
+        if 0 <= index < SIZE:
+            return index
+        raise OutOfBounds(line, col)"
+        }
+        TapeMode::Grow => {
+            "def cell(index, line, col):
+        if index < 0:
+            raise OutOfBounds(line, col)
+        if index >= len(tape):
+            tape.extend(bytes(max(len(tape), index + 1 - len(tape))))
+        return index"
+        }
+    };
+
+    let mut result = format!(
+        "# Generated by rsbfc
+import sys
+
+MASK = {:#x}
+SIZE = {}
+
+
+class OutOfBounds(Exception):
+    def __init__(self, line, col):
+        super().__init__(f\"{} {{line}}:{{col}}\")
+
+
+def run(stdin=None, stdout=None):
+    stdin = stdin or sys.stdin.buffer
+    stdout = stdout or sys.stdout.buffer
+    tape = {}
+    pos = 0
+
+    # index of the cell at index, handles cells outside of the tape
+    {}
+
+",
+        tape.cell_width.max(),
+        tape.size,
+        tape.mode.off_tape_message(),
+        tape_init,
+        cell_fn
+    );
+
+    let mut translator = Translator {
+        result: &mut result,
+        indent: 1,
+        loops: 0,
+        functions: 0,
+        tape,
+        eof,
+    };
+    translator.block(block);
+
+    Ok(result
+        + "    stdout.flush()
+
+
+if __name__ == \"__main__\":
+    try:
+        run()
+    except OutOfBounds as err:
+        sys.stdout.buffer.flush()
+        print(f\"error: {err}\", file=sys.stderr)
+        sys.exit(1)
+")
+}
+
+struct Translator<'a> {
+    result: &'a mut String,
+    indent: usize,
+    // loops nested in the current python function
+    loops: usize,
+    // local functions made for deeply nested loops so far
+    functions: usize,
+    tape: &'a TapeConfig,
+    eof: EofBehavior,
+}
+
+impl Translator<'_> {
+    fn line(&mut self, line: &str) {
+        *self.result += &"    ".repeat(self.indent);
+        *self.result += line;
+        *self.result += "\n";
+    }
+
+    // Expression for the index of the cell at pos + offset
+    fn cell(&self, offset: isize, code_pos: &CodePos) -> String {
+        let index = match offset {
+            0 => "pos".to_string(),
+            _ if offset < 0 => format!("pos - {}", -offset),
+            _ => format!("pos + {}", offset),
+        };
+        format!("cell({}, {}, {})", index, code_pos.line, code_pos.col)
+    }
+
+    // Sets i to the index of the cell at pos + offset
+    fn index(&mut self, offset: isize, code_pos: &CodePos) {
+        let line = format!("i = {}", self.cell(offset, code_pos));
+        self.line(&line);
+    }
+}
+
+impl Emitter for Translator<'_> {
+    fn while_loop(&mut self, code_pos: &CodePos, body: impl FnOnce(&mut Self)) {
+        let function = self.loops == MAX_NESTED_LOOPS;
+        let outer_loops = self.loops;
+        if function {
+            self.functions += 1;
+            let line = format!("def loop_{}():", self.functions);
+            self.line(&line);
+            self.indent += 1;
+            self.line("nonlocal pos");
+            self.loops = 0;
+        }
+
+        let line = format!("while tape[{}]:", self.cell(0, code_pos));
+        self.line(&line);
+        self.indent += 1;
+        self.loops += 1;
+        let start = self.result.len();
+        body(self);
+        if self.result.len() == start {
+            self.line("pass");
+        }
+        self.loops -= 1;
+        self.indent -= 1;
+
+        if function {
+            self.indent -= 1;
+            self.loops = outer_loops;
+            let line = format!("loop_{}()", self.functions);
+            self.line(&line);
+        }
+    }
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos) {
+        match self.tape.mode {
+            TapeMode::Wrap => self.line(&format!("pos = (pos + {}) % SIZE", n)),
+            TapeMode::Strict => {
+                let line = format!("pos = {}", self.cell(n, code_pos));
+                self.line(&line)
+            }
+            _ if n < 0 => self.line(&format!("pos -= {}", -n)),
+            _ => self.line(&format!("pos += {}", n)),
+        }
+    }
+
+    fn add(&mut self, offset: isize, delta: isize, code_pos: &CodePos) {
+        self.index(offset, code_pos);
+        self.line(&format!("tape[i] = (tape[i] + {}) & MASK", delta));
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize, code_pos: &CodePos) {
+        let line = format!("value = tape[{}]", self.cell(0, code_pos));
+        self.line(&line);
+        let guarded = self.tape.mode != TapeMode::Wrap;
+        if guarded {
+            self.line("if value:");
+            self.indent += 1;
+        }
+        self.index(offset, code_pos);
+        let value = match factor {
+            1 => "value".to_string(),
+            _ => format!("value * {}", factor),
+        };
+        self.line(&format!("tape[i] = (tape[i] + {}) & MASK", value));
+        if guarded {
+            self.indent -= 1;
+        }
+    }
+
+    fn clear(&mut self, offset: isize, code_pos: &CodePos) {
+        let line = format!("tape[{}] = 0", self.cell(offset, code_pos));
+        self.line(&line);
+    }
+
+    fn output(&mut self, offset: isize, code_pos: &CodePos) {
+        let line = format!(
+            "stdout.write(bytes((tape[{}] & 0xff,)))",
+            self.cell(offset, code_pos)
+        );
+        self.line(&line);
+    }
+
+    fn input(&mut self, offset: isize, code_pos: &CodePos) {
+        self.index(offset, code_pos);
+        // make sure prompts are visible before blocking on input
+        self.line("stdout.flush()");
+        self.line("byte = stdin.read(1)");
+        self.line("if byte:");
+        self.line("    tape[i] = byte[0]");
+        match self.eof {
+            EofBehavior::Unchanged => {}
+            EofBehavior::Zero => {
+                self.line("else:");
+                self.line("    tape[i] = 0");
+            }
+            EofBehavior::MinusOne => {
+                self.line("else:");
+                self.line("    tape[i] = MASK");
+            }
+        }
+    }
+}
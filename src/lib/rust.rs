@@ -0,0 +1,268 @@
+use crate::{
+    emit::Emitter, ir::Block, CellWidth, CodePos, EofBehavior, Error,
+    TapeConfig, TapeMode,
+};
+
+/*
+Translates to a self contained rust file. The program is in
+`pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()>`
+so the file can be used as a module too, main runs it with locked and
+buffered stdin and stdout. Accessing a cell outside of the tape in
+TapeMode::Error and Strict makes run return an io::Error with the same
+message as the other backends.
+*/
+
+// Translates a program tree to rust
+pub fn rust_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+) -> Result<String, Error> {
+    tape.validate()?;
+    let cell_type = match tape.cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    };
+
+    let cell_fn = match tape.mode {
+        TapeMode::Wrap => "fn cell(tape: &mut Tape, index: isize, _line: usize, _col: usize) -> io::Result<&mut Cell> {
+    Ok(&mut tape[index.rem_euclid(SIZE as isize) as usize])
+}",
+        TapeMode::Error | TapeMode::Strict => "fn cell(tape: &mut Tape, index: isize, line: usize, col: usize) -> io::Result<&mut Cell> {
+    tape.get_mut(index as usize).ok_or_else(|| out_of_bounds(line, col))
+}",
+        TapeMode::Grow => "fn cell(tape: &mut Tape, index: isize, line: usize, col: usize) -> io::Result<&mut Cell> {
+    if index < 0 {
+        return Err(out_of_bounds(line, col));
+    }
+    if index as usize >= tape.len() {
+        let len = (tape.len() * 2).max(index as usize + 1);
+        tape.resize(len, 0);
+    }
+    Ok(&mut tape[index as usize])
+}",
+    };
+    // on the heap, a big tape would overflow the stack
+    let tape_init = match tape.mode {
+        TapeMode::Grow => "vec![0; SIZE]",
+        _ => "vec![0; SIZE].into_boxed_slice()",
+    };
+
+    let mut result = format!(
+        "// Generated by rsbfc
+#![allow(dead_code, unused_assignments, unused_mut, unused_variables)]
+
+use std::io::{{self, Read, Write}};
+
+type Cell = {};
+type Tape = {};
+const SIZE: usize = {};
+
+// The cell at index, handles cells outside of the tape
+{}
+
+fn out_of_bounds(line: usize, col: usize) -> io::Error {{
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!(\"{} {{}}:{{}}\", line, col),
+    )
+}}
+
+// Reads a byte, None at the end of input
+fn read(input: &mut impl Read) -> io::Result<Option<u8>> {{
+    let mut buf = [0];
+    loop {{
+        match input.read(&mut buf) {{
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {{}}
+            Err(err) => return Err(err),
+        }}
+    }}
+}}
+
+pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {{
+    let mut tape: Tape = {};
+    let mut pos: isize = 0;
+",
+        cell_type,
+        match tape.mode {
+            TapeMode::Grow => "Vec<Cell>",
+            _ => "Box<[Cell]>",
+        },
+        tape.size,
+        cell_fn,
+        tape.mode.off_tape_message(),
+        tape_init
+    );
+
+    let mut translator = Translator {
+        result: &mut result,
+        indent: 1,
+        tape,
+        eof,
+    };
+    translator.block(block);
+
+    Ok(result
+        + "    output.flush()
+}
+
+fn main() {
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    if let Err(err) = run(&mut io::stdin().lock(), &mut output) {
+        // keep the order of output and error the same as in the program
+        let _ = output.flush();
+        eprintln!(\"error: {}\", err);
+        std::process::exit(1);
+    }
+}
+")
+}
+
+struct Translator<'a> {
+    result: &'a mut String,
+    indent: usize,
+    tape: &'a TapeConfig,
+    eof: EofBehavior,
+}
+
+impl Translator<'_> {
+    fn line(&mut self, line: &str) {
+        *self.result += &"    ".repeat(self.indent);
+        *self.result += line;
+        *self.result += "\n";
+    }
+
+    // Expression for a mutable reference to the cell at pos + offset
+    fn cell(&self, offset: isize, code_pos: &CodePos) -> String {
+        let index = match offset {
+            0 => "pos".to_string(),
+            _ if offset < 0 => format!("pos - {}", -offset),
+            _ => format!("pos + {}", offset),
+        };
+        format!(
+            "cell(&mut tape, {}, {}, {})?",
+            index, code_pos.line, code_pos.col
+        )
+    }
+
+    // Wrapping add of value, subtracts if that reads better
+    fn wrapping_add(&self, value: isize) -> String {
+        let max = self.tape.cell_width.max() as isize;
+        match value {
+            _ if value < 0 && -value <= max => {
+                format!("wrapping_sub({})", -value)
+            }
+            _ => format!("wrapping_add({})", value & max),
+        }
+    }
+
+    // Emits body in a scope of its own, for tokens that need more than one
+    // statement
+    fn scope(&mut self, body: impl FnOnce(&mut Self)) {
+        self.line("{");
+        self.indent += 1;
+        body(self);
+        self.indent -= 1;
+        self.line("}");
+    }
+}
+
+impl Emitter for Translator<'_> {
+    fn while_loop(&mut self, code_pos: &CodePos, body: impl FnOnce(&mut Self)) {
+        let line = format!("while *{} != 0 {{", self.cell(0, code_pos));
+        self.line(&line);
+        self.indent += 1;
+        body(self);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos) {
+        match self.tape.mode {
+            TapeMode::Wrap => self.line(&format!(
+                "pos = (pos + {}).rem_euclid(SIZE as isize);",
+                n
+            )),
+            _ if n < 0 => self.line(&format!("pos -= {};", -n)),
+            _ => self.line(&format!("pos += {};", n)),
+        }
+        if self.tape.mode == TapeMode::Strict {
+            let line = format!("{};", self.cell(0, code_pos));
+            self.line(&line);
+        }
+    }
+
+    fn add(&mut self, offset: isize, delta: isize, code_pos: &CodePos) {
+        self.scope(|translator| {
+            let line =
+                format!("let cell = {};", translator.cell(offset, code_pos));
+            translator.line(&line);
+            let line =
+                format!("*cell = cell.{};", translator.wrapping_add(delta));
+            translator.line(&line);
+        });
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize, code_pos: &CodePos) {
+        let max = self.tape.cell_width.max() as isize;
+        let value = match factor {
+            1 => "value".to_string(),
+            _ => format!("value.wrapping_mul({})", factor & max),
+        };
+        self.scope(|translator| {
+            let line =
+                format!("let value = *{};", translator.cell(0, code_pos));
+            translator.line(&line);
+            let guarded = translator.tape.mode != TapeMode::Wrap;
+            if guarded {
+                translator.line("if value != 0 {");
+                translator.indent += 1;
+            }
+            let line =
+                format!("let cell = {};", translator.cell(offset, code_pos));
+            translator.line(&line);
+            translator.line(&format!("*cell = cell.wrapping_add({});", value));
+            if guarded {
+                translator.indent -= 1;
+                translator.line("}");
+            }
+        });
+    }
+
+    fn clear(&mut self, offset: isize, code_pos: &CodePos) {
+        let line = format!("*{} = 0;", self.cell(offset, code_pos));
+        self.line(&line);
+    }
+
+    fn output(&mut self, offset: isize, code_pos: &CodePos) {
+        let line = format!(
+            "output.write_all(&[*{} as u8])?;",
+            self.cell(offset, code_pos)
+        );
+        self.line(&line);
+    }
+
+    fn input(&mut self, offset: isize, code_pos: &CodePos) {
+        let eof = self.eof;
+        self.scope(|translator| {
+            let line =
+                format!("let cell = {};", translator.cell(offset, code_pos));
+            translator.line(&line);
+            // make sure prompts are visible before blocking on input
+            translator.line("output.flush()?;");
+            translator.line("match read(input)? {");
+            translator.indent += 1;
+            translator.line("Some(byte) => *cell = byte as Cell,");
+            translator.line(match eof {
+                EofBehavior::Unchanged => "None => {}",
+                EofBehavior::Zero => "None => *cell = 0,",
+                EofBehavior::MinusOne => "None => *cell = Cell::MAX,",
+            });
+            translator.indent -= 1;
+            translator.line("}");
+        });
+    }
+}
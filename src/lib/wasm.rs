@@ -0,0 +1,720 @@
+use crate::{
+    ir::{Block, Node},
+    CellWidth, CodePos, EofBehavior, Error, TapeConfig, TapeMode, TokenKind,
+    OUT_OF_MEMORY_MESSAGE,
+};
+
+/*
+Translates to a WebAssembly module with the tape in linear memory. Without
+WASI the module imports these from "env" and exports "memory" and "run":
+
+    read() -> i32                 next byte of input or -1 at end of input
+    write(value: i32)             outputs the low byte of value
+    out_of_bounds(line: i32, col: i32)
+                                  called before trapping when the tape is
+                                  left in TapeMode::Error, or when the
+                                  pointer leaves it in Strict
+
+A tape that can't grow in TapeMode::Grow traps without calling out_of_bounds.
+With WASI it imports fd_read, fd_write and proc_exit and exports "memory" and
+"_start" instead, errors are written to stderr like the other backends do.
+*/
+
+// Memory layout, the tape is last so it can grow into new pages
+const SCRATCH: i32 = 0;
+const MESSAGE: i32 = 64;
+const OOM_MESSAGE: i32 = 192;
+const TAPE_START: i32 = 1024;
+const PAGE_SIZE: usize = 65536;
+
+// Function indices, imports come first
+const IMPORT_READ: u32 = 0;
+const IMPORT_WRITE: u32 = 1;
+const IMPORT_FAIL: u32 = 2;
+const GETCHAR: u32 = 3;
+const PUTCHAR: u32 = 4;
+const FAIL: u32 = 5;
+const OUT_OF_MEMORY: u32 = 6;
+const CELL: u32 = 7;
+const MAIN: u32 = 8;
+
+// Type indices
+const TYPE_GETCHAR: u32 = 0;
+const TYPE_PUTCHAR: u32 = 1;
+const TYPE_FAIL: u32 = 2;
+const TYPE_FD_IO: u32 = 3;
+const TYPE_CELL: u32 = 4;
+const TYPE_MAIN: u32 = 5;
+
+const I32: u8 = 0x7f;
+
+// Opcodes, memory instructions are followed by alignment and offset
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const DROP: u8 = 0x1a;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const I32_LOAD: u8 = 0x28;
+const I32_LOAD8_U: u8 = 0x2d;
+const I32_LOAD16_U: u8 = 0x2f;
+const I32_STORE: u8 = 0x36;
+const I32_STORE8: u8 = 0x3a;
+const I32_STORE16: u8 = 0x3b;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_CONST: u8 = 0x41;
+const I32_EQZ: u8 = 0x45;
+const I32_EQ: u8 = 0x46;
+const I32_LT_S: u8 = 0x48;
+const I32_GT_S: u8 = 0x4a;
+const I32_LE_S: u8 = 0x4c;
+const I32_GE_U: u8 = 0x4f;
+const I32_ADD: u8 = 0x6a;
+const I32_SUB: u8 = 0x6b;
+const I32_MUL: u8 = 0x6c;
+const I32_DIV_U: u8 = 0x6e;
+const I32_REM_S: u8 = 0x6f;
+const I32_REM_U: u8 = 0x70;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_U: u8 = 0x76;
+// block type of blocks without results
+const EMPTY: u8 = 0x40;
+
+// Translates a program tree to a WebAssembly module, with wasi it runs under
+// a WASI runtime instead of needing the imports above
+pub fn wasm_translate(
+    block: &Block,
+    tape: &TapeConfig,
+    eof: EofBehavior,
+    wasi: bool,
+) -> Result<Vec<u8>, Error> {
+    tape.validate()?;
+    // addresses and the tape size are i32s, keep them positive
+    if TAPE_START as u64 + tape.bytes() as u64 > i32::MAX as u64 {
+        return Err(Error::TapeTooLarge);
+    }
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+
+    let types: [(&[u8], &[u8]); 6] = [
+        (&[], &[I32]),
+        (&[I32], &[]),
+        (&[I32, I32], &[]),
+        (&[I32, I32, I32, I32], &[I32]),
+        (&[I32, I32, I32], &[I32]),
+        (&[], &[]),
+    ];
+    section(&mut module, 1, types.len(), |out| {
+        for (params, results) in types {
+            out.push(0x60);
+            bytes(out, params);
+            bytes(out, results);
+        }
+    });
+
+    let imports = match wasi {
+        false => [
+            ("env", "read", TYPE_GETCHAR),
+            ("env", "write", TYPE_PUTCHAR),
+            ("env", "out_of_bounds", TYPE_FAIL),
+        ],
+        true => [
+            ("wasi_snapshot_preview1", "fd_read", TYPE_FD_IO),
+            ("wasi_snapshot_preview1", "fd_write", TYPE_FD_IO),
+            ("wasi_snapshot_preview1", "proc_exit", TYPE_PUTCHAR),
+        ],
+    };
+    section(&mut module, 2, imports.len(), |out| {
+        for (module, name, type_index) in imports {
+            bytes(out, module.as_bytes());
+            bytes(out, name.as_bytes());
+            out.push(0x00);
+            unsigned(out, type_index);
+        }
+    });
+
+    let functions = [
+        TYPE_GETCHAR,
+        TYPE_PUTCHAR,
+        TYPE_FAIL,
+        TYPE_MAIN,
+        TYPE_CELL,
+        TYPE_MAIN,
+    ];
+    section(&mut module, 3, functions.len(), |out| {
+        for type_index in functions {
+            unsigned(out, type_index);
+        }
+    });
+
+    let pages = (TAPE_START as usize + tape.bytes()).div_ceil(PAGE_SIZE);
+    section(&mut module, 5, 1, |out| {
+        out.push(0x00);
+        unsigned(out, pages as u32);
+    });
+
+    // size of the tape in cells, only changes in TapeMode::Grow
+    section(&mut module, 6, 1, |out| {
+        out.extend_from_slice(&[I32, 0x01, I32_CONST]);
+        signed(out, tape.size as i32);
+        out.push(END);
+    });
+
+    let start = if wasi { "_start" } else { "run" };
+    section(&mut module, 7, 2, |out| {
+        bytes(out, b"memory");
+        out.push(0x02);
+        unsigned(out, 0);
+        bytes(out, start.as_bytes());
+        out.push(0x00);
+        unsigned(out, MAIN);
+    });
+
+    let mut main = Code::default();
+    main.block(block, tape, eof);
+    main.0.push(END);
+    let bodies = [
+        (0, getchar(wasi)),
+        (0, putchar(wasi)),
+        // fail reuses line and col and needs two more with wasi
+        (
+            if wasi { 2 } else { 0 },
+            fail(wasi, &off_tape_message(tape)),
+        ),
+        // fd_write needs local 2
+        (if wasi { 3 } else { 0 }, out_of_memory(wasi)),
+        // new size and pages in TapeMode::Grow
+        (2, cell(tape)),
+        // pos, address and value
+        (3, main.0),
+    ];
+    section(&mut module, 10, bodies.len(), |out| {
+        for (locals, code) in bodies {
+            let mut body = vec![];
+            match locals {
+                0 => unsigned(&mut body, 0),
+                _ => {
+                    unsigned(&mut body, 1);
+                    unsigned(&mut body, locals);
+                    body.push(I32);
+                }
+            }
+            body.extend_from_slice(&code);
+            bytes(out, &body);
+        }
+    });
+
+    let messages = [
+        (MESSAGE, off_tape_message(tape)),
+        (OOM_MESSAGE, out_of_memory_message()),
+    ];
+    section(&mut module, 11, messages.len(), |out| {
+        for (address, message) in messages {
+            out.extend_from_slice(&[0x00, I32_CONST]);
+            signed(out, address);
+            out.push(END);
+            bytes(out, &message);
+        }
+    });
+
+    Ok(module)
+}
+
+// Appends a section with count entries written by contents
+fn section(
+    module: &mut Vec<u8>,
+    id: u8,
+    count: usize,
+    contents: impl FnOnce(&mut Vec<u8>),
+) {
+    let mut out = vec![];
+    unsigned(&mut out, count as u32);
+    contents(&mut out);
+    module.push(id);
+    bytes(module, &out);
+}
+
+// Length prefixed bytes, used for names, vectors of bytes and bodies
+fn bytes(out: &mut Vec<u8>, value: &[u8]) {
+    unsigned(out, value.len() as u32);
+    out.extend_from_slice(value);
+}
+
+// LEB128
+fn unsigned(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0)
+        {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// Instructions of a function body
+#[derive(Default)]
+struct Code(Vec<u8>);
+
+// Locals of main
+const POS: u32 = 0;
+const ADDRESS: u32 = 1;
+const VALUE: u32 = 2;
+
+impl Code {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.0.push(op);
+        self
+    }
+
+    fn with(&mut self, op: u8, immediate: u32) -> &mut Self {
+        self.0.push(op);
+        unsigned(&mut self.0, immediate);
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.0.push(I32_CONST);
+        signed(&mut self.0, value);
+        self
+    }
+
+    fn memory(&mut self, op: u8, align: u32) -> &mut Self {
+        self.with(op, align);
+        unsigned(&mut self.0, 0);
+        self
+    }
+
+    fn load(&mut self, width: CellWidth) -> &mut Self {
+        match width {
+            CellWidth::U8 => self.memory(I32_LOAD8_U, 0),
+            CellWidth::U16 => self.memory(I32_LOAD16_U, 1),
+            CellWidth::U32 => self.memory(I32_LOAD, 2),
+        }
+    }
+
+    fn store(&mut self, width: CellWidth) -> &mut Self {
+        match width {
+            CellWidth::U8 => self.memory(I32_STORE8, 0),
+            CellWidth::U16 => self.memory(I32_STORE16, 1),
+            CellWidth::U32 => self.memory(I32_STORE, 2),
+        }
+    }
+
+    // Pushes the address of the cell at pos + offset
+    fn cell(&mut self, offset: isize, code_pos: &CodePos) -> &mut Self {
+        self.with(LOCAL_GET, POS);
+        if offset != 0 {
+            self.i32(offset as i32).op(I32_ADD);
+        }
+        self.i32(code_pos.line as i32)
+            .i32(code_pos.col as i32)
+            .with(CALL, CELL)
+    }
+
+    fn move_by(&mut self, n: isize, code_pos: &CodePos, tape: &TapeConfig) {
+        match tape.mode {
+            TapeMode::Wrap => {
+                let shift = tape.cell_width.bytes().trailing_zeros() as i32;
+                self.cell(n, code_pos)
+                    .i32(TAPE_START)
+                    .op(I32_SUB)
+                    .i32(shift)
+                    .op(I32_SHR_U)
+                    .with(LOCAL_SET, POS);
+            }
+            _ => {
+                self.with(LOCAL_GET, POS)
+                    .i32(n as i32)
+                    .op(I32_ADD)
+                    .with(LOCAL_SET, POS);
+                if tape.mode == TapeMode::Strict {
+                    self.cell(0, code_pos).op(DROP);
+                }
+            }
+        }
+    }
+
+    // Starts a loop that runs while the current cell is nonzero, closed by
+    // end_loop
+    fn start_loop(&mut self, code_pos: &CodePos, width: CellWidth) {
+        self.op(BLOCK).op(EMPTY).op(LOOP).op(EMPTY);
+        self.cell(0, code_pos)
+            .load(width)
+            .op(I32_EQZ)
+            .with(BR_IF, 1);
+    }
+
+    fn end_loop(&mut self) {
+        self.with(BR, 0).op(END).op(END);
+    }
+
+    // ptr[offset] += *ptr * factor
+    fn mul_add(
+        &mut self,
+        offset: isize,
+        factor: isize,
+        code_pos: &CodePos,
+        tape: &TapeConfig,
+    ) {
+        let width = tape.cell_width;
+        let guarded = tape.mode != TapeMode::Wrap;
+        self.cell(0, code_pos).load(width).with(LOCAL_SET, VALUE);
+        if guarded {
+            self.with(LOCAL_GET, VALUE).op(IF).op(EMPTY);
+        }
+        self.cell(offset, code_pos)
+            .with(LOCAL_TEE, ADDRESS)
+            .with(LOCAL_GET, ADDRESS)
+            .load(width)
+            .with(LOCAL_GET, VALUE);
+        if factor != 1 {
+            self.i32(factor as i32).op(I32_MUL);
+        }
+        self.op(I32_ADD).store(width);
+        if guarded {
+            self.op(END);
+        }
+    }
+
+    fn block(&mut self, block: &Block, tape: &TapeConfig, eof: EofBehavior) {
+        let width = tape.cell_width;
+        for node in block {
+            let token = match node {
+                Node::Loop(inner) => {
+                    self.start_loop(&inner.code_pos, width);
+                    self.block(&inner.body, tape, eof);
+                    self.end_loop();
+                    continue;
+                }
+                Node::Token(token) => token,
+            };
+            let code_pos = &token.code_pos;
+
+            match token.kind {
+                TokenKind::Output { offset } => {
+                    self.cell(offset, code_pos).load(width).with(CALL, PUTCHAR);
+                }
+                TokenKind::Input { offset } => {
+                    self.cell(offset, code_pos)
+                        .with(LOCAL_SET, ADDRESS)
+                        .with(CALL, GETCHAR)
+                        .with(LOCAL_TEE, VALUE)
+                        .i32(-1)
+                        .op(I32_EQ)
+                        .op(IF)
+                        .op(EMPTY);
+                    if let Some(value) = eof.value() {
+                        self.with(LOCAL_GET, ADDRESS)
+                            .i32(value as i32)
+                            .store(width);
+                    }
+                    self.op(ELSE)
+                        .with(LOCAL_GET, ADDRESS)
+                        .with(LOCAL_GET, VALUE)
+                        .store(width)
+                        .op(END);
+                }
+                TokenKind::Clear { offset } => {
+                    self.cell(offset, code_pos).i32(0).store(width);
+                }
+                TokenKind::ValMod { offset, delta } => {
+                    self.cell(offset, code_pos)
+                        .with(LOCAL_TEE, ADDRESS)
+                        .with(LOCAL_GET, ADDRESS)
+                        .load(width)
+                        .i32(delta as i32)
+                        .op(I32_ADD)
+                        .store(width);
+                }
+                TokenKind::PosMod(n) => self.move_by(n, code_pos, tape),
+                TokenKind::Copy(offset) => {
+                    self.mul_add(offset, 1, code_pos, tape)
+                }
+                TokenKind::MulAdd { offset, factor } => {
+                    self.mul_add(offset, factor, code_pos, tape)
+                }
+                TokenKind::Scan(stride) => {
+                    self.start_loop(code_pos, width);
+                    self.move_by(stride, code_pos, tape);
+                    self.end_loop();
+                }
+                TokenKind::Debug { .. } => {}
+                TokenKind::Bracket(_) | TokenKind::Comment => {}
+            }
+        }
+    }
+
+    // fd_write(fd, [address..address + len]), the length is on the stack
+    fn fd_write(&mut self, fd: i32, address: impl FnOnce(&mut Self)) {
+        self.with(LOCAL_SET, 2).i32(SCRATCH);
+        address(self);
+        self.memory(I32_STORE, 2)
+            .i32(SCRATCH + 4)
+            .with(LOCAL_GET, 2)
+            .memory(I32_STORE, 2)
+            .i32(fd)
+            .i32(SCRATCH)
+            .i32(1)
+            .i32(SCRATCH + 8)
+            .with(CALL, IMPORT_WRITE)
+            .op(DROP);
+    }
+
+    // Writes the number in local to stderr, digits are put at the end of the
+    // scratch space from right to left into local 3
+    fn write_number(&mut self, local: u32) {
+        let end = SCRATCH + 64;
+        self.i32(end).with(LOCAL_SET, 3);
+        self.op(LOOP).op(EMPTY);
+        self.with(LOCAL_GET, 3)
+            .i32(1)
+            .op(I32_SUB)
+            .with(LOCAL_TEE, 3)
+            .with(LOCAL_GET, local)
+            .i32(10)
+            .op(I32_REM_U)
+            .i32(b'0' as i32)
+            .op(I32_ADD)
+            .memory(I32_STORE8, 0)
+            .with(LOCAL_GET, local)
+            .i32(10)
+            .op(I32_DIV_U)
+            .with(LOCAL_TEE, local)
+            .with(BR_IF, 0)
+            .op(END);
+        self.i32(end).with(LOCAL_GET, 3).op(I32_SUB);
+        self.fd_write(2, |code| {
+            code.with(LOCAL_GET, 3);
+        });
+    }
+}
+
+fn getchar(wasi: bool) -> Vec<u8> {
+    let mut code = Code::default();
+    if !wasi {
+        code.with(CALL, IMPORT_READ).op(END);
+        return code.0;
+    }
+    // one iovec at SCRATCH pointing to SCRATCH + 16, count at SCRATCH + 8
+    code.i32(SCRATCH)
+        .i32(SCRATCH + 16)
+        .memory(I32_STORE, 2)
+        .i32(SCRATCH + 4)
+        .i32(1)
+        .memory(I32_STORE, 2)
+        .i32(0)
+        .i32(SCRATCH)
+        .i32(1)
+        .i32(SCRATCH + 8)
+        .with(CALL, IMPORT_READ);
+    // errors are treated like the end of input
+    code.op(IF).op(EMPTY).i32(-1).op(RETURN).op(END);
+    code.i32(SCRATCH + 8)
+        .memory(I32_LOAD, 2)
+        .op(I32_EQZ)
+        .op(IF)
+        .op(EMPTY)
+        .i32(-1)
+        .op(RETURN)
+        .op(END);
+    code.i32(SCRATCH + 16).memory(I32_LOAD8_U, 0).op(END);
+    code.0
+}
+
+fn putchar(wasi: bool) -> Vec<u8> {
+    let mut code = Code::default();
+    if !wasi {
+        code.with(LOCAL_GET, 0).with(CALL, IMPORT_WRITE).op(END);
+        return code.0;
+    }
+    code.i32(SCRATCH + 16)
+        .with(LOCAL_GET, 0)
+        .memory(I32_STORE8, 0)
+        .i32(SCRATCH)
+        .i32(SCRATCH + 16)
+        .memory(I32_STORE, 2)
+        .i32(SCRATCH + 4)
+        .i32(1)
+        .memory(I32_STORE, 2)
+        .i32(1)
+        .i32(SCRATCH)
+        .i32(1)
+        .i32(SCRATCH + 8)
+        .with(CALL, IMPORT_WRITE)
+        .op(DROP)
+        .op(END);
+    code.0
+}
+
+// What leaving the tape is reported as with WASI
+fn off_tape_message(tape: &TapeConfig) -> Vec<u8> {
+    format!("error: {} ", tape.mode.off_tape_message()).into_bytes()
+}
+
+fn out_of_memory_message() -> Vec<u8> {
+    format!("error: {}\n", OUT_OF_MEMORY_MESSAGE).into_bytes()
+}
+
+// out_of_memory(), never returns, only traps without WASI
+fn out_of_memory(wasi: bool) -> Vec<u8> {
+    let mut code = Code::default();
+    if wasi {
+        code.i32(out_of_memory_message().len() as i32)
+            .fd_write(2, |code| {
+                code.i32(OOM_MESSAGE);
+            });
+        code.i32(1).with(CALL, IMPORT_FAIL);
+    }
+    code.op(UNREACHABLE).op(END);
+    code.0
+}
+
+// fail(line, col), never returns, writes off_tape_message with WASI
+fn fail(wasi: bool, off_tape_message: &[u8]) -> Vec<u8> {
+    let mut code = Code::default();
+    if !wasi {
+        code.with(LOCAL_GET, 0)
+            .with(LOCAL_GET, 1)
+            .with(CALL, IMPORT_FAIL)
+            .op(UNREACHABLE)
+            .op(END);
+        return code.0;
+    }
+    let message = |code: &mut Code, start: i32, len: usize| {
+        code.i32(len as i32).fd_write(2, |code| {
+            code.i32(start);
+        });
+    };
+    message(&mut code, MESSAGE, off_tape_message.len());
+    code.write_number(0);
+    // ':' and '\n' are written from the start of the scratch space
+    code.i32(SCRATCH + 32)
+        .i32(b':' as i32)
+        .memory(I32_STORE8, 0);
+    message(&mut code, SCRATCH + 32, 1);
+    code.write_number(1);
+    code.i32(SCRATCH + 32)
+        .i32(b'\n' as i32)
+        .memory(I32_STORE8, 0);
+    message(&mut code, SCRATCH + 32, 1);
+    code.i32(1).with(CALL, IMPORT_FAIL).op(UNREACHABLE).op(END);
+    code.0
+}
+
+// cell(index, line, col) -> address, handles accesses outside of the tape
+fn cell(tape: &TapeConfig) -> Vec<u8> {
+    let (index, line, col, new_size, pages) = (0, 1, 2, 3, 4);
+    let shift = tape.cell_width.bytes().trailing_zeros() as i32;
+    let mut code = Code::default();
+    let fail = |code: &mut Code| {
+        code.with(LOCAL_GET, line)
+            .with(LOCAL_GET, col)
+            .with(CALL, FAIL);
+    };
+
+    code.with(LOCAL_GET, index)
+        .with(GLOBAL_GET, 0)
+        .op(I32_GE_U)
+        .op(IF)
+        .op(EMPTY);
+    match tape.mode {
+        TapeMode::Wrap => {
+            code.with(LOCAL_GET, index)
+                .with(GLOBAL_GET, 0)
+                .op(I32_REM_S)
+                .with(LOCAL_TEE, index)
+                .i32(0)
+                .op(I32_LT_S)
+                .op(IF)
+                .op(EMPTY)
+                .with(LOCAL_GET, index)
+                .with(GLOBAL_GET, 0)
+                .op(I32_ADD)
+                .with(LOCAL_SET, index)
+                .op(END);
+        }
+        TapeMode::Error | TapeMode::Strict => fail(&mut code),
+        TapeMode::Grow => {
+            code.with(LOCAL_GET, index)
+                .i32(0)
+                .op(I32_LT_S)
+                .op(IF)
+                .op(EMPTY);
+            fail(&mut code);
+            code.op(END);
+
+            // new pages are zeroed already
+            code.with(GLOBAL_GET, 0)
+                .i32(1)
+                .op(I32_SHL)
+                .with(LOCAL_TEE, new_size)
+                .with(LOCAL_GET, index)
+                .op(I32_LE_S)
+                .op(IF)
+                .op(EMPTY)
+                .with(LOCAL_GET, index)
+                .i32(1)
+                .op(I32_ADD)
+                .with(LOCAL_SET, new_size)
+                .op(END);
+            code.with(LOCAL_GET, new_size)
+                .i32(shift)
+                .op(I32_SHL)
+                .i32(TAPE_START + PAGE_SIZE as i32 - 1)
+                .op(I32_ADD)
+                .i32(PAGE_SIZE.trailing_zeros() as i32)
+                .op(I32_SHR_U)
+                .with(MEMORY_SIZE, 0)
+                .op(I32_SUB)
+                .with(LOCAL_TEE, pages)
+                .i32(0)
+                .op(I32_GT_S)
+                .op(IF)
+                .op(EMPTY)
+                .with(LOCAL_GET, pages)
+                .with(MEMORY_GROW, 0)
+                .i32(-1)
+                .op(I32_EQ)
+                .op(IF)
+                .op(EMPTY)
+                .with(CALL, OUT_OF_MEMORY)
+                .op(END)
+                .op(END);
+            code.with(LOCAL_GET, new_size).with(GLOBAL_SET, 0);
+        }
+    }
+    code.op(END);
+
+    code.with(LOCAL_GET, index)
+        .i32(shift)
+        .op(I32_SHL)
+        .i32(TAPE_START)
+        .op(I32_ADD)
+        .op(END);
+    code.0
+}
@@ -0,0 +1,249 @@
+#![cfg(feature = "jit")]
+
+use std::{fs, path::Path, time::Duration};
+
+use rsbflib::{
+    codegen::Jit, optimize, parse, CellWidth, Dialect, Engine, EofBehavior,
+    Interpreter, Limits, TapeConfig, TapeMode,
+};
+
+/*
+Runs programs on the interpreter and the JIT and checks both of them end the
+same way. Long running programs are cut short by a step limit, both engines
+count the same steps.
+*/
+
+const STEPS: u64 = 20_000_000;
+
+// Output and the Debug form of the error of a run
+fn run(
+    engine: &dyn Engine,
+    source: &str,
+    mode: TapeMode,
+    input: &[u8],
+) -> (Vec<u8>, Option<String>) {
+    let program = optimize(parse(source, Dialect::default()).unwrap(), mode);
+    let mut output = vec![];
+    let result = engine.run(&program, &mut &input[..], &mut output);
+    (output, result.err().map(|error| format!("{:?}", error)))
+}
+
+// Output and error of both engines, which have to be the same
+fn check_limited(
+    name: &str,
+    source: &str,
+    tape: TapeConfig,
+    input: &[u8],
+    limits: Limits,
+) -> (Vec<u8>, Option<String>) {
+    let eof = EofBehavior::Zero;
+    let interpreter = Interpreter { eof, tape, limits };
+    let jit = Jit { eof, tape, limits };
+
+    let expected = run(&interpreter, source, tape.mode, input);
+    let result = run(&jit, source, tape.mode, input);
+    assert_eq!(result, expected, "{}", name);
+    result
+}
+
+fn check(
+    name: &str,
+    source: &str,
+    tape: TapeConfig,
+    input: &[u8],
+) -> (Vec<u8>, Option<String>) {
+    let limits = Limits {
+        steps: Some(STEPS),
+        ..Limits::default()
+    };
+    check_limited(name, source, tape, input, limits)
+}
+
+fn tape(size: usize, mode: TapeMode, cell_width: CellWidth) -> TapeConfig {
+    TapeConfig::new(size, mode, cell_width).unwrap()
+}
+
+#[test]
+fn example_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("bf".as_ref()) {
+            continue;
+        }
+        let source = fs::read_to_string(&path).unwrap();
+        let name = path.display().to_string();
+        check(&name, &source, TapeConfig::default(), b"1\n5\n9\n3\n7\n");
+    }
+}
+
+#[test]
+fn tape_modes() {
+    let programs = [
+        // moves left of the tape and back
+        "+<<++.>>.[-]<<[-<+>]<.",
+        // accesses a cell left of the tape through an offset
+        "+>>+<<<+.",
+        // ends off the tape without touching a cell there, only an error in
+        // strict mode
+        "+.>>>>>>>>>>",
+        // moves right past the end of the tape
+        "+[>+]>>.",
+        "++[>>>+<<<-]>>>.",
+        // leave the tape and come back, merged into no move at all unless
+        // strict mode keeps them apart
+        "<>+.",
+        ">>>>>>>><<<<<<<<+.",
+        // leaves the tape and comes back inside a copy loop
+        "+[->>>><<+<<]>>.",
+        "+[->>>>>>>><<<<<<+<<]>>.",
+    ];
+    for mode in [TapeMode::Wrap, TapeMode::Error, TapeMode::Strict] {
+        for program in programs {
+            let name = format!("{} {:?}", program, mode);
+            check(&name, program, tape(8, mode, CellWidth::U8), b"");
+        }
+    }
+    // a run of moves that leaves the tape fails where it starts
+    let error = "PointerOffTape(CodePos { line: 1, col: 1, offset: 0 })";
+    for program in ["<>+.", ">>>>>>>><<<<<<<<+."] {
+        let tape = tape(8, TapeMode::Strict, CellWidth::U8);
+        let result = check(program, program, tape, b"");
+        assert_eq!(result, (vec![], Some(error.to_string())), "{}", program);
+    }
+    // a loop that leaves the tape isn't replaced in strict mode
+    let program = "+[->>>><<+<<]>>.";
+    let result = check(
+        program,
+        program,
+        tape(4, TapeMode::Strict, CellWidth::U8),
+        b"",
+    );
+    let error = "PointerOffTape(CodePos { line: 1, col: 4, offset: 3 })";
+    assert_eq!(result, (vec![], Some(error.to_string())));
+    for program in programs {
+        let name = format!("{} Grow", program);
+        check(&name, program, tape(2, TapeMode::Grow, CellWidth::U8), b"");
+    }
+}
+
+#[test]
+fn cell_widths() {
+    let programs = [
+        // counts every value of the cell width into the next cell
+        "+[>+<+]>.",
+        "-.+.",
+        ",[>+>+<<-]>>.",
+    ];
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        for program in programs {
+            let name = format!("{} {:?}", program, cell_width);
+            let tape = tape(30000, TapeMode::Wrap, cell_width);
+            check(&name, program, tape, b"\xff");
+        }
+    }
+}
+
+#[test]
+fn mul_add_loops() {
+    let programs = [
+        "+++++[>+++<-]>.",
+        "+++[->++>---<<]>.>.",
+        "+++[>+<-]>.",
+        // the loop cell is 0, the target is off the tape and never touched
+        "[<<+>>-]+.",
+        "+[<<+>>-]<<.",
+        // the target is left unchanged, but still off the tape
+        "+[-<<+->>]+.",
+    ];
+    for mode in [TapeMode::Wrap, TapeMode::Error, TapeMode::Grow] {
+        for program in programs {
+            let name = format!("{} {:?}", program, mode);
+            check(&name, program, tape(16, mode, CellWidth::U8), b"");
+        }
+    }
+}
+
+#[test]
+fn scan_loops() {
+    let programs = [
+        "+>+>+>>+<<<<[>]+.",
+        ">+>+>+[<]>.",
+        "+>>>+>>>+>>>>[<<<]>+.",
+        // every cell is nonzero, the scan wraps or leaves the tape
+        "+>+>+>+>+>+>+>+[>]+.",
+        "+>+>+>+>+>+>+>+[<]+.",
+        "+>+>+>+>+>+>+>+<<<<<<<[>>>]+.",
+        "+[<<<]+.",
+    ];
+    for mode in [TapeMode::Wrap, TapeMode::Error, TapeMode::Strict] {
+        for program in programs {
+            let name = format!("{} {:?}", program, mode);
+            check(&name, program, tape(8, mode, CellWidth::U8), b"");
+        }
+    }
+    for program in programs {
+        let name = format!("{} Grow", program);
+        check(&name, program, tape(4, TapeMode::Grow, CellWidth::U8), b"");
+    }
+}
+
+#[test]
+fn step_limits() {
+    let source = "+.+.+.";
+    let limited = |steps| {
+        let limits = Limits {
+            steps,
+            ..Limits::default()
+        };
+        let name = format!("{} {:?}", source, steps);
+        check_limited(&name, source, TapeConfig::default(), b"", limits)
+    };
+    assert_eq!(limited(None), (b"\x01\x02\x03".to_vec(), None));
+    assert_eq!(limited(Some(6)), (b"\x01\x02\x03".to_vec(), None));
+    // the JIT stops before output the interpreter doesn't reach
+    let error = Some("LimitExceeded(Steps(2))".to_string());
+    assert_eq!(limited(Some(2)), (b"\x01".to_vec(), error));
+    let error = Some("LimitExceeded(Steps(5))".to_string());
+    assert_eq!(limited(Some(5)), (b"\x01\x02".to_vec(), error));
+}
+
+#[test]
+fn time_limits() {
+    let source = "++++++++[>++++++++<-]>+.";
+    let limited = |time| {
+        let limits = Limits {
+            time,
+            ..Limits::default()
+        };
+        let name = format!("{} {:?}", source, time);
+        check_limited(&name, source, TapeConfig::default(), b"", limits)
+    };
+    assert_eq!(limited(None), (b"A".to_vec(), None));
+    assert_eq!(
+        limited(Some(Duration::from_secs(60))),
+        (b"A".to_vec(), None)
+    );
+    // out of time before the first token
+    let error = Some("LimitExceeded(Time(0ns))".to_string());
+    assert_eq!(limited(Some(Duration::ZERO)), (vec![], error));
+}
+
+#[test]
+fn output_limits() {
+    let source = "+.+.+.";
+    let limited = |output| {
+        let limits = Limits {
+            output,
+            ..Limits::default()
+        };
+        let name = format!("{} {:?}", source, output);
+        check_limited(&name, source, TapeConfig::default(), b"", limits)
+    };
+    assert_eq!(limited(None), (b"\x01\x02\x03".to_vec(), None));
+    assert_eq!(limited(Some(3)), (b"\x01\x02\x03".to_vec(), None));
+    let error = Some("LimitExceeded(Output(2))".to_string());
+    assert_eq!(limited(Some(2)), (b"\x01\x02".to_vec(), error));
+    let error = Some("LimitExceeded(Output(0))".to_string());
+    assert_eq!(limited(Some(0)), (vec![], error));
+}
@@ -0,0 +1,39 @@
+use std::{env, fs, process::Command};
+
+/*
+Runs the rsbfc binary to check which target it picks without --target.
+*/
+
+// Exit code and output of rsbfc compiling source with args
+fn rsbfc(name: &str, source: &str, args: &[&str]) -> (Option<i32>, String) {
+    let path = env::temp_dir().join(format!(
+        "rsbfc-{}-{}.bf",
+        std::process::id(),
+        name
+    ));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rsbfc"))
+        .arg(&path)
+        .args(args)
+        .output()
+        .unwrap();
+    let _ = fs::remove_file(path);
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn code_prints_c_by_default() {
+    let (status, code) = rsbfc("default", "+.", &["--code"]);
+    assert_eq!(status, Some(0));
+    assert!(code.contains("int main()"), "{}", code);
+
+    let (status, code) = rsbfc("llvm", "+.", &["--code", "--target", "llvm"]);
+    assert_eq!(status, Some(0));
+    assert!(code.contains("define i32 @main()"), "{}", code);
+
+    let binary = ["--code", "--target", "elf"];
+    assert_eq!(rsbfc("elf", "+.", &binary), (Some(1), String::new()));
+}
@@ -0,0 +1,51 @@
+use std::{env, fs, process::Command};
+
+/*
+Runs the rsbfi binary to check its exit codes: 0 when the program ends, 2
+when it hits a limit and 1 for every other error.
+*/
+
+// Exit code and output of rsbfi running source with args
+fn rsbfi(name: &str, source: &str, args: &[&str]) -> (Option<i32>, Vec<u8>) {
+    let path = env::temp_dir().join(format!(
+        "rsbf-{}-{}.bf",
+        std::process::id(),
+        name
+    ));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rsbfi"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    let _ = fs::remove_file(path);
+    (output.status.code(), output.stdout)
+}
+
+#[test]
+fn limits_exit_with_2() {
+    let source = "+.+.+.";
+    let mut engines = vec![vec![]];
+    if cfg!(feature = "jit") {
+        engines.push(vec!["--jit"]);
+    }
+    for engine in engines {
+        let run = |name, limit: &[&str]| {
+            let args = [&engine[..], limit].concat();
+            rsbfi(&format!("{}{}", name, engine.len()), source, &args)
+        };
+        assert_eq!(run("none", &[]), (Some(0), b"\x01\x02\x03".to_vec()));
+        let steps = run("steps", &["--max-steps", "2"]);
+        assert_eq!(steps, (Some(2), b"\x01".to_vec()));
+        let time = run("time", &["--timeout", "0"]);
+        assert_eq!(time, (Some(2), vec![]));
+        let output = run("output", &["--max-output", "2"]);
+        assert_eq!(output, (Some(2), b"\x01\x02".to_vec()));
+    }
+}
+
+#[test]
+fn other_errors_exit_with_1() {
+    let args = ["--tape-mode", "error", "--max-steps", "100"];
+    assert_eq!(rsbfi("error", "+.<+", &args), (Some(1), b"\x01".to_vec()));
+}
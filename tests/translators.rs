@@ -0,0 +1,391 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use rsbflib::{
+    ir::Block, js_translate, llvm_translate, optimize, parse, python_translate,
+    rust_translate, wasm_translate, CellWidth, Dialect, Engine, EofBehavior,
+    Error, Interpreter, TapeConfig, TapeMode,
+};
+
+/*
+Checks the output of the translators and, where the toolchain to run it is
+installed, runs the translated programs and compares their output with the
+interpreter. Missing tools skip the run, not the test.
+*/
+
+// Uses a mul add loop, a clear loop, a scan loop and input
+const SAMPLE: &str = "+++[->++<]>[<],.";
+const INPUT: &[u8] = b"hello";
+
+fn program(source: &str) -> Block {
+    optimize(parse(source, Dialect::default()).unwrap(), TapeMode::Wrap)
+}
+
+fn example(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(name);
+    fs::read_to_string(path).unwrap()
+}
+
+// Cells of the tape the programs run with, small so they can leave it
+const RUN_TAPE_SIZE: usize = 8;
+
+// A program run by every translator, with what the interpreter does with it
+struct Run {
+    source: String,
+    tape: TapeConfig,
+    program: Block,
+    output: Vec<u8>,
+    // false if the interpreter stopped with an error
+    success: bool,
+}
+
+// The programs run in every tape mode with every cell width
+fn runs() -> Vec<Run> {
+    let sources = [
+        example("copy-loop.bf"),
+        example("letter-a.bf"),
+        SAMPLE.to_string(),
+        ",[.,]".to_string(),
+        // leaves the tape to the left after some output
+        "+.<.".to_string(),
+        // leaves the tape to the right, which grows it
+        ">>>>>>>>>>++++++++[<++++++++>-]<+.".to_string(),
+        // prints A unless the cell wraps around at 256
+        "++++++++[>++++++++++++++++++++++++++++++++<-]>[<+++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.>[-]]".to_string(),
+    ];
+    let mut runs = vec![];
+    for mode in [
+        TapeMode::Wrap,
+        TapeMode::Error,
+        TapeMode::Strict,
+        TapeMode::Grow,
+    ] {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            let tape =
+                TapeConfig::new(RUN_TAPE_SIZE, mode, cell_width).unwrap();
+            let interpreter = Interpreter {
+                eof: EofBehavior::Zero,
+                tape,
+                ..Interpreter::default()
+            };
+            for source in &sources {
+                let program =
+                    optimize(parse(source, Dialect::default()).unwrap(), mode);
+                let mut output = vec![];
+                let success = interpreter
+                    .run(&program, &mut &INPUT[..], &mut output)
+                    .is_ok();
+                runs.push(Run {
+                    source: source.clone(),
+                    tape,
+                    program,
+                    output,
+                    success,
+                });
+            }
+        }
+    }
+    runs
+}
+
+impl Run {
+    // Checks what a translated program did against the interpreter
+    fn check(&self, (output, success): (Vec<u8>, bool), tool: &str) {
+        assert_eq!(
+            (output, success),
+            (self.output.clone(), self.success),
+            "{} {:?} {}",
+            tool,
+            self.tape,
+            self.source
+        );
+    }
+}
+
+// A file in the temp directory that doesn't collide with other test runs
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("rsbf-{}-{}", std::process::id(), name))
+}
+
+// Stdout of a command given input and whether it succeeded, None if the
+// command can't be started
+fn run_tool(command: &mut Command, input: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // programs that stop reading early close stdin
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let output = child.wait_with_output().unwrap();
+    Some((output.stdout, output.status.success()))
+}
+
+// Ids and contents of the sections of a WebAssembly module
+fn wasm_sections(module: &[u8]) -> Vec<(u8, &[u8])> {
+    assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+    let mut sections = vec![];
+    let mut rest = &module[8..];
+    while let Some((&id, tail)) = rest.split_first() {
+        let (mut size, mut shift, mut len) = (0, 0, 0);
+        loop {
+            let byte = tail[len];
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        sections.push((id, &tail[len..len + size]));
+        rest = &tail[len + size..];
+    }
+    sections
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+// Runs a module with node, feeding the env imports from stdin and stdout
+const WASM_ENV_RUNNER: &str = "
+const fs = require('fs');
+const input = fs.readFileSync(0);
+let pos = 0;
+const output = [];
+const env = {
+    read: () => pos < input.length ? input[pos++] : -1,
+    write: value => output.push(value & 255),
+    out_of_bounds: () => {},
+};
+const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+try {
+    new WebAssembly.Instance(wasm, { env }).exports.run();
+} finally {
+    fs.writeSync(1, Buffer.from(output));
+}
+";
+
+const WASM_WASI_RUNNER: &str = "
+const fs = require('fs');
+const { WASI } = require('wasi');
+const wasi = new WASI({ version: 'preview1' });
+const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+// proc_exit makes start return the exit code
+process.exitCode = wasi.start(
+    new WebAssembly.Instance(wasm, wasi.getImportObject()),
+);
+";
+
+#[test]
+fn wasm_sections_and_imports() {
+    let tape = TapeConfig::default();
+    for (wasi, imports, start) in [
+        (false, ["env", "read", "write", "out_of_bounds"], "run"),
+        (
+            true,
+            ["wasi_snapshot_preview1", "fd_read", "fd_write", "proc_exit"],
+            "_start",
+        ),
+    ] {
+        let module =
+            wasm_translate(&program(SAMPLE), &tape, EofBehavior::Zero, wasi)
+                .unwrap();
+        let sections = wasm_sections(&module);
+        let ids: Vec<u8> = sections.iter().map(|&(id, _)| id).collect();
+        // type, import, function, memory, global, export, code and data
+        assert_eq!(ids, [1, 2, 3, 5, 6, 7, 10, 11], "wasi: {}", wasi);
+        let import = sections[1].1;
+        for name in imports {
+            assert!(contains(import, name), "{} missing", name);
+        }
+        let export = sections[5].1;
+        assert!(contains(export, "memory") && contains(export, start));
+        let data = sections[7].1;
+        assert!(contains(data, "error: out of memory\n"));
+    }
+}
+
+#[test]
+fn wasm_runs_like_the_interpreter() {
+    for (wasi, runner) in [(false, WASM_ENV_RUNNER), (true, WASM_WASI_RUNNER)] {
+        let script = temp_path(&format!("wasm-{}.js", wasi));
+        let path = temp_path(&format!("wasm-{}.wasm", wasi));
+        fs::write(&script, runner).unwrap();
+        for run in runs() {
+            let module = wasm_translate(
+                &run.program,
+                &run.tape,
+                EofBehavior::Zero,
+                wasi,
+            )
+            .unwrap();
+            fs::write(&path, module).unwrap();
+            let mut node = Command::new("node");
+            let Some(result) = run_tool(node.arg(&script).arg(&path), INPUT)
+            else {
+                break;
+            };
+            run.check(result, &format!("wasi: {}", wasi));
+        }
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn llvm_golden() {
+    let tape = TapeConfig::default();
+    let code =
+        llvm_translate(&program(SAMPLE), &tape, EofBehavior::MinusOne).unwrap();
+    for snippet in [
+        // +++
+        "%new2 = add i8 %old2, 3",
+        // [->++<]
+        "%product5 = mul i8 %value4, 2",
+        "%sum5 = add i8 %old5, %product5",
+        // [<]
+        "%moved12 = add i64 %pos12, -1",
+        // , at the end of input
+        "%new14 = select i1 %eof14, i8 -1, i8 %read14",
+    ] {
+        assert!(code.contains(snippet), "{} missing in\n{}", snippet, code);
+    }
+}
+
+// Flags lli needs for opaque pointers, LLVM 14 has them behind one and newer
+// versions only have them. None if lli isn't installed
+fn lli_flags() -> Option<&'static [&'static str]> {
+    let probe = temp_path("probe.ll");
+    fs::write(&probe, "define ptr @f() {\n  ret ptr null\n}\n").unwrap();
+    let flags = [&[][..], &["-opaque-pointers"]].into_iter().find(|flags| {
+        Command::new("lli")
+            .args(*flags)
+            .arg("-entry-function=f")
+            .arg(&probe)
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    });
+    let _ = fs::remove_file(probe);
+    flags
+}
+
+#[test]
+fn llvm_runs_like_the_interpreter() {
+    let Some(flags) = lli_flags() else {
+        return;
+    };
+    let path = temp_path("llvm.ll");
+    for run in runs() {
+        let code =
+            llvm_translate(&run.program, &run.tape, EofBehavior::Zero).unwrap();
+        fs::write(&path, code).unwrap();
+        let mut lli = Command::new("lli");
+        run.check(run_tool(lli.args(flags).arg(&path), INPUT).unwrap(), "lli");
+    }
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn rust_golden() {
+    let tape = TapeConfig::default();
+    let code =
+        rust_translate(&program(SAMPLE), &tape, EofBehavior::MinusOne).unwrap();
+    for snippet in [
+        "*cell = cell.wrapping_add(3);",
+        "*cell = cell.wrapping_add(value.wrapping_mul(2));",
+        "while *cell(&mut tape, pos, 1, 12)? != 0 {
+        pos = (pos + -1).rem_euclid(SIZE as isize);",
+        "None => *cell = Cell::MAX,",
+    ] {
+        assert!(code.contains(snippet), "{} missing in\n{}", snippet, code);
+    }
+}
+
+#[test]
+fn rust_runs_like_the_interpreter() {
+    let path = temp_path("rust.rs");
+    let binary = temp_path("rust");
+    for run in runs() {
+        let code =
+            rust_translate(&run.program, &run.tape, EofBehavior::Zero).unwrap();
+        fs::write(&path, code).unwrap();
+        let mut rustc = Command::new("rustc");
+        rustc
+            .args(["--edition", "2021", "-o"])
+            .arg(&binary)
+            .arg(&path);
+        let Some((_, compiled)) = run_tool(&mut rustc, b"") else {
+            break;
+        };
+        assert!(compiled, "{:?} {}", run.tape, run.source);
+        let result = run_tool(&mut Command::new(&binary), INPUT).unwrap();
+        run.check(result, "rustc");
+    }
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(binary);
+}
+
+#[test]
+fn js_golden() {
+    let tape = TapeConfig::default();
+    let code =
+        js_translate(&program(SAMPLE), &tape, EofBehavior::MinusOne).unwrap();
+    for snippet in [
+        "tape[i] += 3;",
+        "tape[i] += Math.imul(value, 2);",
+        "while ((i = cell(pos, 1, 12), tape[i]) !== 0) {
+    pos = cell(pos - 1, 1, 12);",
+        "tape[i] = value == null ? -1 : value;",
+    ] {
+        assert!(code.contains(snippet), "{} missing in\n{}", snippet, code);
+    }
+}
+
+#[test]
+fn python_golden() {
+    let tape = TapeConfig::default();
+    let code = python_translate(&program(SAMPLE), &tape, EofBehavior::MinusOne)
+        .unwrap();
+    for snippet in [
+        "tape[i] = (tape[i] + 3) & MASK",
+        "tape[i] = (tape[i] + value * 2) & MASK",
+        "while tape[cell(pos, 1, 12)]:
+        pos = (pos + -1) % SIZE",
+        "tape[i] = MASK",
+    ] {
+        assert!(code.contains(snippet), "{} missing in\n{}", snippet, code);
+    }
+}
+
+type Translate = fn(&Block, &TapeConfig, EofBehavior) -> Result<String, Error>;
+
+#[test]
+fn js_and_python_run_like_the_interpreter() {
+    let translators: [(&str, Translate); 2] =
+        [("node", js_translate), ("python3", python_translate)];
+    for (tool, translate) in translators {
+        let path = temp_path(&format!("{}-program", tool));
+        for run in runs() {
+            let code =
+                translate(&run.program, &run.tape, EofBehavior::Zero).unwrap();
+            fs::write(&path, code).unwrap();
+            let mut command = Command::new(tool);
+            let Some(result) = run_tool(command.arg(&path), INPUT) else {
+                break;
+            };
+            run.check(result, tool);
+        }
+        let _ = fs::remove_file(path);
+    }
+}